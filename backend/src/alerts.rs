@@ -0,0 +1,749 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{
+    controller::{Controller, Status},
+    estimate::{describe_remaining, Estimate, MIN_NOTIFICATION_CONFIDENCE},
+    history::write_atomic,
+    protocol::{BatteryAlert, Digest, ServerMessage},
+    settings::{Settings, SuppressedAlerts},
+};
+
+// Batches of alerts a slow subscriber can fall behind by before it misses some
+const ALERT_CHANNEL_CAPACITY: usize = 16;
+
+/// The alerts of one check of the controllers
+pub type AlertBatch = Arc<Vec<ServerMessage>>;
+
+/// How low a controller is, critical alerts repeat more often
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AlertLevel {
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LastAlert {
+    /// Unix time in seconds
+    pub time: u64,
+    pub level: AlertLevel,
+}
+
+/// Bookkeeping of the alerts sent, keyed by `Controller::stable_id` so it survives reconnects
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AlertState {
+    /// Last low battery alert for each controller
+    pub last_alerts: HashMap<String, LastAlert>,
+    /// Controllers whose low battery alert was acknowledged, until they aren't low anymore
+    pub acknowledged: HashSet<String>,
+    /// Controllers whose alerts are snoozed, with the timestamp the snooze ends
+    pub snoozed_until: HashMap<String, u64>,
+    /// Timestamp until which every alert is held back, like a quiet period
+    pub snoozed_all_until: Option<u64>,
+    /// Alerts held back during quiet time, for the digest
+    pub held: Vec<HeldAlert>,
+}
+
+/// The latest alert of a controller held back during quiet time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeldAlert {
+    /// `Controller::id` of the controller
+    pub id: String,
+    pub message: String,
+}
+
+/// Alert state shared by every client, so that a reconnecting frontend or a restart doesn't
+/// repeat alerts before their cooldown is over. The alerts themselves are published to every
+/// subscriber, e.g. the websocket clients and the webhooks.
+pub struct AlertService {
+    file_path: PathBuf,
+    state: Mutex<AlertState>,
+    sender: broadcast::Sender<AlertBatch>,
+}
+
+impl AlertService {
+    pub async fn new(file_path: impl AsRef<Path>) -> Result<Self> {
+        let file_path = file_path.as_ref().to_path_buf();
+        if let Some(dir) = file_path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let state = match tokio::fs::read(&file_path).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                error!("Ignoring the alert state, failed to parse it: {}", err);
+                AlertState::default()
+            }),
+            Err(err) => {
+                debug!("No alert state yet: {}", err);
+                AlertState::default()
+            }
+        };
+        let (sender, _) = broadcast::channel(ALERT_CHANNEL_CAPACITY);
+        Ok(Self {
+            file_path,
+            state: Mutex::new(state),
+            sender,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AlertBatch> {
+        self.sender.subscribe()
+    }
+
+    /// Sends the alerts of a check, or the error it failed with, to every subscriber
+    pub fn publish(&self, alerts: Vec<ServerMessage>) {
+        if alerts.is_empty() {
+            return;
+        }
+        // Nobody listening isn't an error, the alerts are just dropped
+        let _ = self.sender.send(Arc::new(alerts));
+    }
+
+    /// Runs `f` on the alert state and saves it if `f` changed it. Failing to save is logged
+    /// rather than returned, the state in memory stays up to date either way.
+    pub async fn update<R>(&self, f: impl FnOnce(&mut AlertState) -> R) -> R {
+        let mut state = self.state.lock().await;
+        let before = state.clone();
+        let result = f(&mut state);
+        if *state != before {
+            if let Err(err) = self.save(&state).await {
+                error!("Failed to save the alert state: {:#}", err);
+            }
+        }
+        result
+    }
+
+    async fn save(&self, state: &AlertState) -> Result<()> {
+        write_atomic(&self.file_path, &serde_json::to_vec(state)?).await
+    }
+}
+
+/// Fills in a message template. `{name}` is the controller's name, `{nickname}` its nickname or
+/// its name when it has none, `{capacity}` the battery level in percent and `{remaining}` the
+/// estimated time left, e.g. "about 25 minutes left".
+pub fn render(
+    template: &str,
+    controller: &Controller,
+    nickname: Option<&str>,
+    minutes_remaining: Option<u32>,
+) -> String {
+    let remaining = match minutes_remaining {
+        Some(minutes) => describe_remaining(minutes),
+        None => "time left unknown".to_string(),
+    };
+    template
+        .replace("{name}", &controller.name)
+        .replace("{nickname}", nickname.unwrap_or(&controller.name))
+        .replace("{capacity}", &controller.capacity.to_string())
+        .replace("{remaining}", &remaining)
+}
+
+/// Returns the low battery and fully charged alerts for this check. Low battery alerts are
+/// repeated for as long as the controller stays low, at the interval the settings give for its
+/// level, unless a client acknowledged or snoozed them. Dropping from warning to critical
+/// alerts right away, even if the warning was acknowledged. Alerts mention the time left when
+/// `estimates` is confident enough.
+pub fn battery_alerts(
+    previous: Option<&[Controller]>,
+    current: &[Controller],
+    estimates: &HashMap<String, Estimate>,
+    settings: &Settings,
+    alerts: &mut AlertState,
+) -> Vec<ServerMessage> {
+    let mut messages = Vec::new();
+    let now = unix_now();
+    alerts.snoozed_until.retain(|_, until| *until > now);
+
+    for controller in current {
+        let id = controller.stable_id();
+        let thresholds = settings.alerts_for(&id);
+        let level = match controller.capacity {
+            _ if !controller.is_discharging() => None,
+            capacity if capacity < thresholds.critical_threshold => Some(AlertLevel::Critical),
+            capacity if capacity < thresholds.warning_threshold => Some(AlertLevel::Warning),
+            _ => None,
+        };
+        debug!("Controller {} is low battery: {:?}", controller.name, level);
+        if level.is_none() {
+            alerts.acknowledged.remove(&id);
+        }
+        if alerts.snoozed_until.contains_key(&id) {
+            debug!("Alerts snoozed for controller {}", controller.name);
+            continue;
+        }
+
+        let last_alert = alerts.last_alerts.get(&id).copied();
+        let escalated = level
+            .zip(last_alert)
+            .is_some_and(|(level, last_alert)| level > last_alert.level);
+        if escalated {
+            alerts.acknowledged.remove(&id);
+        }
+
+        let enabled = settings.events.low_battery;
+        if let Some(level) = level.filter(|_| enabled && !alerts.acknowledged.contains(&id)) {
+            let repeat_minutes = match level {
+                AlertLevel::Warning => thresholds.warning_repeat_minutes,
+                AlertLevel::Critical => thresholds.critical_repeat_minutes,
+            };
+            let due = match last_alert {
+                None => true,
+                Some(last_alert) => {
+                    let last_alert_secs_ago = now.saturating_sub(last_alert.time);
+                    debug!(
+                        "Last alert was {} seconds ago for controller {}",
+                        last_alert_secs_ago, controller.name
+                    );
+                    escalated || last_alert_secs_ago >= repeat_minutes as u64 * 60
+                }
+            };
+
+            if due {
+                let time_left = estimates
+                    .get(&id)
+                    .filter(|estimate| estimate.confidence >= MIN_NOTIFICATION_CONFIDENCE)
+                    .and_then(|estimate| estimate.minutes_remaining);
+                let templates = settings.templates_for(&id);
+                let (template, low) = match level {
+                    AlertLevel::Warning => (templates.low_battery, "low"),
+                    AlertLevel::Critical => (templates.critical_battery, "critically low"),
+                };
+                let message =
+                    alert_text(
+                        template,
+                        controller,
+                        settings,
+                        time_left,
+                        |name| match time_left {
+                            Some(minutes) => format!(
+                                "{} is {} on battery ({}%), {}",
+                                name,
+                                low,
+                                controller.capacity,
+                                describe_remaining(minutes)
+                            ),
+                            None => {
+                                format!("{} is {} on battery ({}%)", name, low, controller.capacity)
+                            }
+                        },
+                    );
+                let mut alert = BatteryAlert::new(controller, message);
+                alert.critical = level == AlertLevel::Critical;
+                messages.push(ServerMessage::LowBattery(alert));
+
+                alerts
+                    .last_alerts
+                    .insert(id.clone(), LastAlert { time: now, level });
+            }
+        }
+
+        let was_below_full = previous
+            .and_then(|previous| previous.iter().find(|p| p.id() == controller.id()))
+            .is_some_and(|previous| previous.capacity < 100);
+        if settings.events.charged
+            && was_below_full
+            && controller.capacity == 100
+            && !controller.is_discharging()
+        {
+            let template = settings.templates_for(&id).charged;
+            let message = alert_text(template, controller, settings, None, |name| {
+                format!("{} is fully charged, unplug it", name)
+            });
+            messages.push(ServerMessage::Charged(BatteryAlert::new(
+                controller, message,
+            )));
+        }
+    }
+
+    messages
+}
+
+/// The controllers that disappeared since the previous check while discharging below their
+/// warning threshold, unless `requested` tells their disconnect was asked for, e.g. by the user
+/// turning them off. They most likely ran out of battery.
+pub fn ran_flat<'a>(
+    previous: &'a [Controller],
+    current: &[Controller],
+    settings: &Settings,
+    requested: impl Fn(&Controller) -> bool,
+) -> Vec<&'a Controller> {
+    previous
+        .iter()
+        .filter(|controller| {
+            let thresholds = settings.alerts_for(&controller.stable_id());
+            !current.iter().any(|c| c.id() == controller.id())
+                && controller.is_discharging()
+                && controller.capacity < thresholds.warning_threshold
+                && !requested(controller)
+        })
+        .collect()
+}
+
+/// Returns the alerts for the controllers that connected, disconnected or started charging since
+/// the previous check, as enabled in the settings. The controllers in `flat`, as found by
+/// `ran_flat`, get their own alert instead of the disconnected one.
+pub fn transition_alerts(
+    previous: &[Controller],
+    current: &[Controller],
+    flat: &[&Controller],
+    settings: &Settings,
+    alerts: &AlertState,
+) -> Vec<ServerMessage> {
+    let mut messages = Vec::new();
+    let now = unix_now();
+    let events = settings.events;
+    let snoozed = |controller: &Controller| {
+        alerts
+            .snoozed_until
+            .get(&controller.stable_id())
+            .is_some_and(|until| *until > now)
+    };
+
+    for controller in current.iter().filter(|controller| !snoozed(controller)) {
+        match previous.iter().find(|p| p.id() == controller.id()) {
+            None if events.connected => {
+                let template = settings.templates_for(&controller.stable_id()).connected;
+                let message = alert_text(template, controller, settings, None, |name| {
+                    format!("{} connected ({}%)", name, controller.capacity)
+                });
+                messages.push(ServerMessage::ConnectedAlert(BatteryAlert::new(
+                    controller, message,
+                )));
+            }
+            Some(previous)
+                if events.charging_started
+                    && previous.status != Status::Charging
+                    && controller.status == Status::Charging
+                    && controller.capacity < 100 =>
+            {
+                let template = settings
+                    .templates_for(&controller.stable_id())
+                    .charging_started;
+                let message = alert_text(template, controller, settings, None, |name| {
+                    format!("{} is charging ({}%)", name, controller.capacity)
+                });
+                messages.push(ServerMessage::ChargingStarted(BatteryAlert::new(
+                    controller, message,
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    for controller in previous.iter().filter(|controller| !snoozed(controller)) {
+        if current.iter().any(|c| c.id() == controller.id()) {
+            continue;
+        }
+        let templates = settings.templates_for(&controller.stable_id());
+        let ran_flat = flat.iter().any(|c| c.id() == controller.id());
+        if ran_flat && events.lost_while_low {
+            let template = templates.lost_while_low;
+            let message = alert_text(template, controller, settings, None, |name| {
+                format!(
+                    "{} likely ran out of battery (last seen at {}%)",
+                    name, controller.capacity
+                )
+            });
+            let mut alert = BatteryAlert::new(controller, message);
+            alert.critical = true;
+            messages.push(ServerMessage::LostWhileLow(alert));
+        } else if events.disconnected {
+            let template = templates.disconnected;
+            let message = alert_text(template, controller, settings, None, |name| {
+                format!("{} disconnected", name)
+            });
+            messages.push(ServerMessage::DisconnectedAlert(BatteryAlert::new(
+                controller, message,
+            )));
+        }
+    }
+
+    messages
+}
+
+/// The text of an alert about `controller`: its template from the settings if there's one,
+/// otherwise `default` given the controller's nickname or name
+fn alert_text(
+    template: Option<String>,
+    controller: &Controller,
+    settings: &Settings,
+    minutes_remaining: Option<u32>,
+    default: impl FnOnce(&str) -> String,
+) -> String {
+    let nickname = settings.nickname(&controller.stable_id());
+    match template {
+        Some(template) => render(&template, controller, nickname, minutes_remaining),
+        None => default(nickname.unwrap_or(&controller.name)),
+    }
+}
+
+/// Holds back the alerts during quiet time. Depending on the settings they are dropped, or kept
+/// for a digest sent with the first check after the quiet time, which only has the latest alert
+/// of each controller.
+pub fn hold_alerts(
+    messages: Vec<ServerMessage>,
+    quiet: bool,
+    suppressed: SuppressedAlerts,
+    alerts: &mut AlertState,
+) -> Vec<ServerMessage> {
+    if quiet {
+        for alert in messages.iter().filter_map(ServerMessage::alert) {
+            debug!("Quiet time, holding back: {}", alert.message);
+            if suppressed == SuppressedAlerts::Digest {
+                alerts.held.retain(|held| held.id != alert.id);
+                alerts.held.push(HeldAlert {
+                    id: alert.id.clone(),
+                    message: alert.message.clone(),
+                });
+            }
+        }
+        return Vec::new();
+    }
+    if alerts.held.is_empty() {
+        return messages;
+    }
+
+    let held: Vec<String> = alerts.held.drain(..).map(|held| held.message).collect();
+    let digest = Digest {
+        message: format!("During quiet time: {}", held.join("; ")),
+        alerts: held,
+    };
+    let mut messages = messages;
+    messages.insert(0, ServerMessage::Digest(digest));
+    messages
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        battery_alerts, hold_alerts, ran_flat, render, transition_alerts, AlertLevel, AlertService,
+        AlertState, LastAlert,
+    };
+    use crate::controller::{Controller, Status};
+    use crate::estimate::Estimate;
+    use crate::protocol::ServerMessage;
+    use crate::settings::{ControllerSettings, Settings, SuppressedAlerts};
+
+    fn controller(path: &str, capacity: u8, status: Status) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity,
+            status,
+            bluetooth: true,
+            serial_number: Some(path.trim_start_matches("/dev/").to_string()),
+            device_path: Some(path.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let controller = Controller {
+            name: "Pro Controller".to_string(),
+            product_id: 0x2009,
+            vendor_id: 0x057e,
+            capacity: 25,
+            status: Status::Discharging,
+            bluetooth: true,
+            serial_number: None,
+            device_path: None,
+        };
+        let template = "{nickname} ({name}) is at {capacity}%, {remaining}";
+        assert_eq!(
+            render(template, &controller, Some("Player 2"), Some(40)),
+            "Player 2 (Pro Controller) is at 25%, about 40 minutes left"
+        );
+        assert_eq!(
+            render(template, &controller, None, None),
+            "Pro Controller (Pro Controller) is at 25%, time left unknown"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_alert_state_persists() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("test_alerts_{}", std::process::id()));
+        let file_path = dir.join("alerts.json");
+
+        let service = AlertService::new(&file_path).await?;
+        service
+            .update(|state| {
+                let last_alert = LastAlert {
+                    time: 1000,
+                    level: AlertLevel::Critical,
+                };
+                state
+                    .last_alerts
+                    .insert("054c-0ce6".to_string(), last_alert);
+                state.acknowledged.insert("057e-2009".to_string());
+            })
+            .await;
+
+        // As if the backend restarted
+        let service = AlertService::new(&file_path).await?;
+        let state = service.update(|state| state.clone()).await;
+        assert_eq!(state.last_alerts["054c-0ce6"].level, AlertLevel::Critical);
+        assert!(state.acknowledged.contains("057e-2009"));
+
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_battery_alerts() {
+        let settings = Settings::default();
+        let mut alerts = AlertState::default();
+        let previous = vec![controller("/dev/hidraw1", 95, Status::Charging)];
+        let current = vec![
+            controller("/dev/hidraw1", 100, Status::Charging),
+            controller("/dev/hidraw2", 15, Status::Discharging),
+        ];
+
+        let messages = battery_alerts(
+            Some(&previous),
+            &current,
+            &HashMap::new(),
+            &settings,
+            &mut alerts,
+        );
+        assert_eq!(messages.len(), 2);
+        assert!(
+            matches!(&messages[0], ServerMessage::Charged(alert) if alert.id == "/dev/hidraw1")
+        );
+        assert!(
+            matches!(&messages[1], ServerMessage::LowBattery(alert) if alert.message == "DualSense is low on battery (15%)")
+        );
+
+        // The low battery alert is on cooldown and the controller was already full
+        assert!(battery_alerts(
+            Some(&current),
+            &current,
+            &HashMap::new(),
+            &settings,
+            &mut alerts
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_battery_alert_time_left() {
+        let settings = Settings::default();
+        let current = vec![controller("/dev/hidraw2", 15, Status::Discharging)];
+        let mut estimates = HashMap::from([(
+            "054c-0ce6-hidraw2".to_string(),
+            Estimate {
+                minutes_remaining: Some(24),
+                minutes_to_full: None,
+                confidence: 0.8,
+            },
+        )]);
+
+        let messages = battery_alerts(
+            None,
+            &current,
+            &estimates,
+            &settings,
+            &mut AlertState::default(),
+        );
+        assert!(
+            matches!(&messages[0], ServerMessage::LowBattery(alert) if alert.message == "DualSense is low on battery (15%), about 25 minutes left")
+        );
+
+        // Not confident enough to tell
+        estimates.get_mut("054c-0ce6-hidraw2").unwrap().confidence = 0.1;
+        let messages = battery_alerts(
+            None,
+            &current,
+            &estimates,
+            &settings,
+            &mut AlertState::default(),
+        );
+        assert!(
+            matches!(&messages[0], ServerMessage::LowBattery(alert) if alert.message == "DualSense is low on battery (15%)")
+        );
+    }
+
+    #[test]
+    fn test_acknowledged_and_snoozed_alerts() {
+        let settings = Settings::default();
+        let mut alerts = AlertState::default();
+        let low = vec![controller("/dev/hidraw1", 15, Status::Discharging)];
+
+        alerts.acknowledged.insert("054c-0ce6-hidraw1".to_string());
+        assert!(battery_alerts(None, &low, &HashMap::new(), &settings, &mut alerts).is_empty());
+
+        // The acknowledgement is dropped once the controller is charging
+        let charging = vec![controller("/dev/hidraw1", 15, Status::Charging)];
+        assert!(
+            battery_alerts(None, &charging, &HashMap::new(), &settings, &mut alerts).is_empty()
+        );
+        assert!(alerts.acknowledged.is_empty());
+
+        alerts
+            .snoozed_until
+            .insert("054c-0ce6-hidraw1".to_string(), u64::MAX);
+        assert!(battery_alerts(None, &low, &HashMap::new(), &settings, &mut alerts).is_empty());
+
+        // Expired snoozes are forgotten
+        alerts
+            .snoozed_until
+            .insert("054c-0ce6-hidraw1".to_string(), 0);
+        assert_eq!(
+            battery_alerts(None, &low, &HashMap::new(), &settings, &mut alerts).len(),
+            1
+        );
+        assert!(alerts.snoozed_until.is_empty());
+    }
+
+    #[test]
+    fn test_alert_thresholds() {
+        let mut settings = Settings::default();
+        let mut alerts = AlertState::default();
+
+        // Below the warning threshold, then below the critical one
+        let low = vec![controller("/dev/hidraw1", 15, Status::Discharging)];
+        let messages = battery_alerts(None, &low, &HashMap::new(), &settings, &mut alerts);
+        assert!(matches!(&messages[0], ServerMessage::LowBattery(alert) if !alert.critical));
+        alerts.acknowledged.insert("054c-0ce6-hidraw1".to_string());
+
+        // Getting worse alerts right away, even if the warning was acknowledged
+        let critical = vec![controller("/dev/hidraw1", 5, Status::Discharging)];
+        let messages = battery_alerts(None, &critical, &HashMap::new(), &settings, &mut alerts);
+        assert!(
+            matches!(&messages[0], ServerMessage::LowBattery(alert) if alert.critical && alert.message == "DualSense is critically low on battery (5%)")
+        );
+        assert!(
+            battery_alerts(None, &critical, &HashMap::new(), &settings, &mut alerts).is_empty()
+        );
+
+        // A controller with coarse steps can warn earlier
+        settings.controllers.insert(
+            "054c-0ce6-hidraw1".to_string(),
+            ControllerSettings {
+                warning_threshold: Some(30),
+                ..Default::default()
+            },
+        );
+        let mut alerts = AlertState::default();
+        let quarter = vec![controller("/dev/hidraw1", 25, Status::Discharging)];
+        assert_eq!(
+            battery_alerts(None, &quarter, &HashMap::new(), &settings, &mut alerts).len(),
+            1
+        );
+        assert!(battery_alerts(
+            None,
+            &quarter,
+            &HashMap::new(),
+            &Settings::default(),
+            &mut AlertState::default()
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_transition_alerts() {
+        let mut settings = Settings::default();
+        let alerts = AlertState::default();
+        let previous = vec![
+            controller("/dev/hidraw1", 50, Status::Discharging),
+            controller("/dev/hidraw2", 5, Status::Discharging),
+            controller("/dev/hidraw3", 60, Status::Discharging),
+        ];
+        let current = vec![
+            controller("/dev/hidraw1", 50, Status::Charging),
+            controller("/dev/hidraw4", 80, Status::Discharging),
+        ];
+
+        // Only running flat is on by default
+        let flat = ran_flat(&previous, &current, &settings, |_| false);
+        assert_eq!(flat.len(), 1);
+        let messages = transition_alerts(&previous, &current, &flat, &settings, &alerts);
+        assert_eq!(messages.len(), 1);
+        assert!(
+            matches!(&messages[0], ServerMessage::LostWhileLow(alert) if alert.id == "/dev/hidraw2" && alert.message == "DualSense likely ran out of battery (last seen at 5%)")
+        );
+
+        // Turned off on purpose
+        let flat = ran_flat(&previous, &current, &settings, |_| true);
+        assert!(flat.is_empty());
+        assert!(transition_alerts(&previous, &current, &flat, &settings, &alerts).is_empty());
+        let flat = ran_flat(&previous, &current, &settings, |_| false);
+
+        settings.events.connected = true;
+        settings.events.disconnected = true;
+        settings.events.charging_started = true;
+        settings.events.lost_while_low = false;
+        let messages = transition_alerts(&previous, &current, &flat, &settings, &alerts);
+        assert_eq!(messages.len(), 4);
+        assert!(
+            matches!(&messages[0], ServerMessage::ChargingStarted(alert) if alert.message == "DualSense is charging (50%)")
+        );
+        assert!(
+            matches!(&messages[1], ServerMessage::ConnectedAlert(alert) if alert.message == "DualSense connected (80%)")
+        );
+        assert!(
+            matches!(&messages[2], ServerMessage::DisconnectedAlert(alert) if alert.id == "/dev/hidraw2")
+        );
+        assert!(
+            matches!(&messages[3], ServerMessage::DisconnectedAlert(alert) if alert.id == "/dev/hidraw3")
+        );
+
+        let mut alerts = AlertState::default();
+        alerts
+            .snoozed_until
+            .insert("054c-0ce6-hidraw3".to_string(), u64::MAX);
+        assert_eq!(
+            transition_alerts(&previous, &current, &flat, &settings, &alerts).len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_hold_alerts() {
+        let mut alerts = AlertState::default();
+        let low = vec![controller("/dev/hidraw1", 15, Status::Discharging)];
+        let lower = vec![controller("/dev/hidraw1", 5, Status::Discharging)];
+        let settings = Settings::default();
+
+        let messages = battery_alerts(None, &low, &HashMap::new(), &settings, &mut alerts);
+        assert!(hold_alerts(messages, true, SuppressedAlerts::Digest, &mut alerts).is_empty());
+        let messages = battery_alerts(None, &lower, &HashMap::new(), &settings, &mut alerts);
+        assert!(hold_alerts(messages, true, SuppressedAlerts::Digest, &mut alerts).is_empty());
+
+        // Only the latest alert of the controller makes it into the digest
+        let messages = hold_alerts(Vec::new(), false, SuppressedAlerts::Digest, &mut alerts);
+        assert_eq!(messages.len(), 1);
+        assert!(
+            matches!(&messages[0], ServerMessage::Digest(digest) if digest.message == "During quiet time: DualSense is critically low on battery (5%)")
+        );
+        assert!(alerts.held.is_empty());
+
+        let mut alerts = AlertState::default();
+        let messages = battery_alerts(None, &low, &HashMap::new(), &settings, &mut alerts);
+        assert!(hold_alerts(messages, true, SuppressedAlerts::Drop, &mut alerts).is_empty());
+        assert!(hold_alerts(Vec::new(), false, SuppressedAlerts::Drop, &mut alerts).is_empty());
+    }
+}
@@ -1,14 +1,26 @@
 mod bluetooth;
+pub mod fake;
 mod generic;
 mod nintendo;
 mod playstation;
 mod xbox;
-use anyhow::Result;
-use hidapi::HidApi;
+use anyhow::{bail, Result};
+use hidapi::{DeviceInfo, HidApi};
 use log::debug;
+use serde::{Deserialize, Serialize};
 use udev::Enumerator;
 
-use crate::controller::{Controller, Status};
+use crate::{
+    controller::{Controller, Status},
+    metrics,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatteryInfo {
+    pub capacity: u8,
+    pub status: Status,
+}
 
 pub async fn controllers_async() -> Result<Vec<Controller>> {
     // Spawn a tokio blocking task because `get_controllers()` is a blocking API
@@ -24,6 +36,7 @@ pub fn controllers() -> Result<Vec<Controller>> {
     if cfg!(debug_assertions) {
         parse_fake_controller(&mut controllers);
     }
+    controllers.extend(fake::controllers()?);
 
     // HidApi will return 2 copies of the device when the Nintendo Pro Controller is connected via USB.
     // It will additionally return a 3rd device when the controller is connected via Bluetooth + USB.
@@ -38,7 +51,9 @@ pub fn controllers() -> Result<Vec<Controller>> {
     if nintendo_pro_controllers.len() == 1 || nintendo_pro_controllers.len() == 2 {
         // When we only get one device, we know it's connected via Bluetooth.
         // When we get two devices, we know it's connected only via USB. Both will report the same data, so we'll just return the first one.
-        let controller = nintendo::parse_controller_data(nintendo_pro_controllers[0], &hidapi)?;
+        let controller = probe("nintendo", nintendo_pro_controllers[0], || {
+            nintendo::parse_controller_data(nintendo_pro_controllers[0], &hidapi)
+        })?;
         controllers.push(controller);
     } else if nintendo_pro_controllers.len() == 3 {
         // When we get three devices, we know it's connected via USB + Bluetooth.
@@ -48,7 +63,9 @@ pub fn controllers() -> Result<Vec<Controller>> {
             .find(|device_info| device_info.interface_number() == -1);
 
         if let Some(bt_controller) = bt_controller {
-            let controller = nintendo::parse_controller_data(bt_controller, &hidapi)?;
+            let controller = probe("nintendo", bt_controller, || {
+                nintendo::parse_controller_data(bt_controller, &hidapi)
+            })?;
             controllers.push(controller);
         }
     }
@@ -61,7 +78,9 @@ pub fn controllers() -> Result<Vec<Controller>> {
         })
         .collect();
     for device_info in nintendo_non_pro_controllers {
-        let controller = nintendo::parse_controller_data(device_info, &hidapi)?;
+        let controller = probe("nintendo", device_info, || {
+            nintendo::parse_controller_data(device_info, &hidapi)
+        })?;
         controllers.push(controller);
     }
 
@@ -85,28 +104,38 @@ pub fn controllers() -> Result<Vec<Controller>> {
         match (device_info.vendor_id(), device_info.product_id()) {
             (xbox::MS_VENDOR_ID, xbox::XBOX_ONE_S_CONTROLLER_BT_PRODUCT_ID) => {
                 debug!("!Found Xbox One S controller: {:?}", device_info);
-                let controller = xbox::parse_xbox_controller_data(device_info, &hidapi)?;
+                let controller = probe("xbox", device_info, || {
+                    xbox::parse_xbox_controller_data(device_info, &hidapi)
+                })?;
                 controllers.push(controller);
             }
             (xbox::MS_VENDOR_ID, xbox::XBOX_ONE_S_LATEST_FW_PRODUCT_ID) => {
                 debug!("Found Xbox One S controller: {:?}", device_info);
-                let controller = xbox::parse_xbox_controller_data(device_info, &hidapi)?;
+                let controller = probe("xbox", device_info, || {
+                    xbox::parse_xbox_controller_data(device_info, &hidapi)
+                })?;
 
                 controllers.push(controller);
             }
             (xbox::MS_VENDOR_ID, xbox::XBOX_WIRELESS_CONTROLLER_BT_PRODUCT_ID) => {
                 debug!("Found Xbox Series X/S controller: {:?}", device_info);
-                let controller = xbox::parse_xbox_controller_data(device_info, &hidapi)?;
+                let controller = probe("xbox", device_info, || {
+                    xbox::parse_xbox_controller_data(device_info, &hidapi)
+                })?;
                 controllers.push(controller);
             }
             (xbox::MS_VENDOR_ID, xbox::XBOX_WIRELESS_ELITE_CONTROLLER_BT_PRODUCT_ID) => {
                 debug!("Found Xbox Elite 2 controller: {:?}", device_info);
-                let controller = xbox::parse_xbox_controller_data(device_info, &hidapi)?;
+                let controller = probe("xbox", device_info, || {
+                    xbox::parse_xbox_controller_data(device_info, &hidapi)
+                })?;
                 controllers.push(controller);
             }
             (xbox::MS_VENDOR_ID, xbox::XBOX_WIRELESS_ELITE_CONTROLLER_BTLE_PRODUCT_ID) => {
                 debug!("Found Xbox Elite 2 controller: {:?}", device_info);
-                let controller = xbox::parse_xbox_controller_data(device_info, &hidapi)?;
+                let controller = probe("xbox", device_info, || {
+                    xbox::parse_xbox_controller_data(device_info, &hidapi)
+                })?;
                 controllers.push(controller);
             }
             _ => {}
@@ -119,45 +148,49 @@ pub fn controllers() -> Result<Vec<Controller>> {
         match (device_info.vendor_id(), device_info.product_id()) {
             (playstation::DS_VENDOR_ID, playstation::DS3_PRODUCT_ID) => {
                 debug!("Found DualShock3 controller: {:?}", device_info);
-                let controller = playstation::parse_dualshock3_controller_data(
-                    device_info,
-                    &hidapi,
-                    "DualShock3",
-                )?;
+                let controller = probe("playstation", device_info, || {
+                    playstation::parse_dualshock3_controller_data(
+                        device_info,
+                        &hidapi,
+                        "DualShock3",
+                    )
+                })?;
 
                 controllers.push(controller);
             }
             (playstation::DS_VENDOR_ID, playstation::DS_PRODUCT_ID) => {
                 debug!("Found DualSense controller: {:?}", device_info);
-                let controller = playstation::parse_dualsense_controller_data(
-                    device_info,
-                    &hidapi,
-                    "DualSense",
-                )?;
+                let controller = probe("playstation", device_info, || {
+                    playstation::parse_dualsense_controller_data(device_info, &hidapi, "DualSense")
+                })?;
 
                 controllers.push(controller);
             }
             (playstation::DS_VENDOR_ID, playstation::DS_EDGE_PRODUCT_ID) => {
                 debug!("Found DualSense Edge controller: {:?}", device_info);
-                let controller = playstation::parse_dualsense_controller_data(
-                    device_info,
-                    &hidapi,
-                    "DualSense Edge",
-                )?;
+                let controller = probe("playstation", device_info, || {
+                    playstation::parse_dualsense_controller_data(
+                        device_info,
+                        &hidapi,
+                        "DualSense Edge",
+                    )
+                })?;
 
                 controllers.push(controller);
             }
             (playstation::DS_VENDOR_ID, playstation::DS4_NEW_PRODUCT_ID) => {
                 debug!("Found new DualShock 4 controller: {:?}", device_info);
-                let controller =
-                    playstation::parse_dualshock_controller_data(device_info, &hidapi)?;
+                let controller = probe("playstation", device_info, || {
+                    playstation::parse_dualshock_controller_data(device_info, &hidapi)
+                })?;
 
                 controllers.push(controller);
             }
             (playstation::DS_VENDOR_ID, playstation::DS4_OLD_PRODUCT_ID) => {
                 debug!("Found old DualShock 4 controller: {:?}", device_info);
-                let controller =
-                    playstation::parse_dualshock_controller_data(device_info, &hidapi)?;
+                let controller = probe("playstation", device_info, || {
+                    playstation::parse_dualshock_controller_data(device_info, &hidapi)
+                })?;
 
                 controllers.push(controller);
             }
@@ -174,7 +207,9 @@ pub fn controllers() -> Result<Vec<Controller>> {
         .collect();
     unknown_controllers.dedup_by(|a, b| a.path() == b.path());
     for device_info in unknown_controllers {
-        let controller = generic::get_controller_data(device_info, &hidapi)?;
+        let controller = probe("generic", device_info, || {
+            generic::get_controller_data(device_info, &hidapi)
+        })?;
         controllers.push(controller);
     }
 
@@ -185,14 +220,82 @@ pub fn controllers() -> Result<Vec<Controller>> {
         let mut controller =
             Controller::from_udev(&device, "Unknown Controller", 0, Status::Unknown, false);
         if xbox::is_xbox_controller(controller.vendor_id) {
-            xbox::update_xbox_controller(&mut controller, false);
+            let (vendor_id, product_id) = (controller.vendor_id, controller.product_id);
+            metrics::global().probe("xbox", vendor_id, product_id, || {
+                xbox::update_xbox_controller(&mut controller, false);
+                Ok(())
+            })?;
             controllers.push(controller);
         }
     }
 
+    metrics::global().record_controllers(&controllers);
     Ok(controllers)
 }
 
+/// Probes a HID device with the given driver, recording how long it took and whether it failed
+fn probe<T>(
+    driver: &str,
+    device_info: &DeviceInfo,
+    probe: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    metrics::global().probe(
+        driver,
+        device_info.vendor_id(),
+        device_info.product_id(),
+        probe,
+    )
+}
+
+/// Size of the capacity buckets a controller reports: Nintendo controllers only report 5 levels,
+/// Sony ones 10% steps. Others report their actual percentage.
+pub fn capacity_step(vendor_id: u16) -> u8 {
+    match vendor_id {
+        nintendo::VENDOR_ID_NINTENDO => 25,
+        playstation::DS_VENDOR_ID => 10,
+        _ => 1,
+    }
+}
+
+/// Whether the battery of a vendor's controllers is decoded from their HID reports. BlueZ
+/// doesn't know about these batteries, unlike e.g. the ones of Xbox controllers.
+pub fn hid_battery(vendor_id: u16) -> bool {
+    matches!(
+        vendor_id,
+        nintendo::VENDOR_ID_NINTENDO | playstation::DS_VENDOR_ID
+    )
+}
+
+/// Runs a raw input report through the parser `controllers()` uses for this device. Returns
+/// `None` when the parser ignores the report, and an error for devices whose battery isn't read
+/// from their input reports, e.g. Xbox controllers that go through bluetoothctl.
+pub fn parse_report(
+    vendor_id: u16,
+    product_id: u16,
+    bluetooth: bool,
+    report: &[u8],
+) -> Result<Option<BatteryInfo>> {
+    match (vendor_id, product_id) {
+        (playstation::DS_VENDOR_ID, playstation::DS3_PRODUCT_ID) => {
+            Ok(playstation::parse_dualshock3_report(report))
+        }
+        (playstation::DS_VENDOR_ID, playstation::DS_PRODUCT_ID)
+        | (playstation::DS_VENDOR_ID, playstation::DS_EDGE_PRODUCT_ID) => {
+            playstation::parse_dualsense_report(report, bluetooth)
+        }
+        (playstation::DS_VENDOR_ID, playstation::DS4_NEW_PRODUCT_ID)
+        | (playstation::DS_VENDOR_ID, playstation::DS4_OLD_PRODUCT_ID) => {
+            playstation::parse_dualshock_report(report, bluetooth).map(Some)
+        }
+        (nintendo::VENDOR_ID_NINTENDO, _) => nintendo::parse_report(report).map(Some),
+        _ => bail!(
+            "No input report parser for {:04x}:{:04x}",
+            vendor_id,
+            product_id
+        ),
+    }
+}
+
 fn parse_fake_controller(controllers: &mut Vec<Controller>) {
     if let Ok(file) = std::fs::File::open("/tmp/fake_controller.json") {
         let controller = match serde_json::from_reader(file) {
@@ -0,0 +1,330 @@
+//! Scripted fake controllers, to exercise the notifications and the frontend without hardware.
+//!
+//! A scenario is a JSON file listing fake controllers and how they change over time:
+//!
+//! ```json
+//! {
+//!   "repeat": false,
+//!   "controllers": [
+//!     {
+//!       "name": "DualSense",
+//!       "vendorId": 1356,
+//!       "productId": 3302,
+//!       "bluetooth": true,
+//!       "steps": [
+//!         { "at": 0, "capacity": 30, "status": "discharging" },
+//!         { "at": 120, "capacity": 10 },
+//!         { "at": 150, "connected": false },
+//!         { "at": 180, "connected": true, "capacity": 10, "status": "charging" },
+//!         { "at": 300, "capacity": 100 },
+//!         { "at": 310, "error": "Failed to read the input report" }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `at` is in seconds since the scenario started. Every field of a step is optional and stays in
+//! effect until a later step changes it, except `error` which only lasts until the next step and
+//! makes the whole probe fail, like a controller that can't be read. The capacity moves linearly
+//! from one step to the next step that sets it, which gives discharge and charge curves. A
+//! controller isn't connected before its first step. With `repeat`, the scenario starts over
+//! after its last step.
+
+use std::{path::Path, sync::Mutex, time::Instant};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::controller::{Controller, Status};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Scenario {
+    #[serde(default)]
+    pub repeat: bool,
+    pub controllers: Vec<FakeController>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FakeController {
+    /// Used as the controller's device path and serial number, defaults to `fake<index>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub vendor_id: u16,
+    #[serde(default)]
+    pub product_id: u16,
+    #[serde(default)]
+    pub bluetooth: bool,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+    pub at: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connected: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bluetooth: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScenarioState {
+    pub scenario: Scenario,
+    pub elapsed_seconds: f64,
+}
+
+struct RunningScenario {
+    scenario: Scenario,
+    started: Instant,
+}
+
+// `api::controllers` is a free function called from the server, the websocket sessions and the
+// command line, so the running scenario lives next to it rather than in the app state
+static SCENARIO: Mutex<Option<RunningScenario>> = Mutex::new(None);
+
+impl Scenario {
+    pub fn validate(&self) -> Result<()> {
+        for controller in &self.controllers {
+            if controller.steps.is_empty() {
+                bail!("Fake controller {} has no steps", controller.name);
+            }
+            if let Some(step) = controller.steps.iter().find(|step| !step.at.is_finite()) {
+                bail!(
+                    "Fake controller {} has a step at {}",
+                    controller.name,
+                    step.at
+                );
+            }
+            for (previous, step) in controller.steps.iter().zip(&controller.steps[1..]) {
+                if step.at < previous.at {
+                    bail!(
+                        "Steps of fake controller {} are not in order at {}s",
+                        controller.name,
+                        step.at
+                    );
+                }
+            }
+            if let Some(step) = controller
+                .steps
+                .iter()
+                .find(|step| step.capacity > Some(100))
+            {
+                bail!(
+                    "Fake controller {} has a capacity above 100% at {}s",
+                    controller.name,
+                    step.at
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn duration(&self) -> f64 {
+        self.controllers
+            .iter()
+            .filter_map(|controller| controller.steps.last())
+            .map(|step| step.at)
+            .fold(0.0, f64::max)
+    }
+
+    /// The fake controllers `elapsed` seconds into the scenario
+    pub fn controllers_at(&self, elapsed: f64) -> Result<Vec<Controller>> {
+        let duration = self.duration();
+        let elapsed = if self.repeat && duration > 0.0 {
+            elapsed % duration
+        } else {
+            elapsed
+        };
+
+        let mut controllers = Vec::new();
+        for (index, fake) in self.controllers.iter().enumerate() {
+            let count = fake
+                .steps
+                .iter()
+                .take_while(|step| step.at <= elapsed)
+                .count();
+            let past = &fake.steps[..count];
+            let Some(current) = past.last() else {
+                continue;
+            };
+            if let Some(error) = &current.error {
+                bail!("{}: {}", fake.name, error);
+            }
+            if past.iter().rev().find_map(|step| step.connected) == Some(false) {
+                continue;
+            }
+
+            let id = fake.id.clone().unwrap_or(format!("fake{}", index));
+            let status = past.iter().rev().find_map(|step| step.status);
+            let bluetooth = past.iter().rev().find_map(|step| step.bluetooth);
+            controllers.push(Controller {
+                name: fake.name.clone(),
+                product_id: fake.product_id,
+                vendor_id: fake.vendor_id,
+                capacity: fake.capacity_at(elapsed),
+                status: status.unwrap_or(Status::Unknown),
+                bluetooth: bluetooth.unwrap_or(fake.bluetooth),
+                serial_number: Some(id.clone()),
+                device_path: Some(id),
+            });
+        }
+        Ok(controllers)
+    }
+}
+
+impl FakeController {
+    fn capacity_at(&self, elapsed: f64) -> u8 {
+        let keyframes = self
+            .steps
+            .iter()
+            .filter_map(|step| step.capacity.map(|capacity| (step.at, capacity as f64)));
+        let mut previous: Option<(f64, f64)> = None;
+        for (at, capacity) in keyframes {
+            if at > elapsed {
+                return match previous {
+                    Some((previous_at, previous_capacity)) => {
+                        let progress = (elapsed - previous_at) / (at - previous_at);
+                        (previous_capacity + (capacity - previous_capacity) * progress).round()
+                            as u8
+                    }
+                    None => 0,
+                };
+            }
+            previous = Some((at, capacity));
+        }
+        previous.map_or(0, |(_, capacity)| capacity as u8)
+    }
+}
+
+pub fn load(file_path: &Path) -> Result<Scenario> {
+    let contents = std::fs::read(file_path)
+        .with_context(|| format!("Failed to read {}", file_path.display()))?;
+    serde_json::from_slice(&contents)
+        .with_context(|| format!("Failed to parse {}", file_path.display()))
+}
+
+/// Replaces the running scenario, which starts now
+pub fn start(scenario: Scenario) -> Result<()> {
+    scenario.validate()?;
+    *SCENARIO.lock().unwrap() = Some(RunningScenario {
+        scenario,
+        started: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Stops the running scenario, the fake controllers disconnect
+pub fn reset() {
+    *SCENARIO.lock().unwrap() = None;
+}
+
+pub fn state() -> Option<ScenarioState> {
+    SCENARIO
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|running| ScenarioState {
+            scenario: running.scenario.clone(),
+            elapsed_seconds: running.started.elapsed().as_secs_f64(),
+        })
+}
+
+/// The fake controllers of the running scenario, if any
+pub fn controllers() -> Result<Vec<Controller>> {
+    match SCENARIO.lock().unwrap().as_ref() {
+        Some(running) => running
+            .scenario
+            .controllers_at(running.started.elapsed().as_secs_f64()),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::Status;
+
+    use super::Scenario;
+
+    const SCENARIO: &str = r#"{
+        "controllers": [
+            {
+                "name": "DualSense",
+                "bluetooth": true,
+                "steps": [
+                    { "at": 0, "capacity": 30, "status": "discharging" },
+                    { "at": 100, "capacity": 10 },
+                    { "at": 150, "connected": false },
+                    { "at": 180, "connected": true, "status": "charging", "bluetooth": false },
+                    { "at": 200, "error": "Failed to read" },
+                    { "at": 210 }
+                ]
+            },
+            {
+                "id": "pro",
+                "name": "Pro Controller",
+                "steps": [{ "at": 50, "capacity": 100, "status": "charging" }]
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_controllers_at() {
+        let scenario: Scenario = serde_json::from_str(SCENARIO).unwrap();
+        scenario.validate().unwrap();
+
+        let controllers = scenario.controllers_at(0.0).unwrap();
+        assert_eq!(controllers.len(), 1);
+        assert_eq!(controllers[0].id(), "fake0");
+        assert_eq!(controllers[0].capacity, 30);
+        assert_eq!(controllers[0].status, Status::Discharging);
+        assert!(controllers[0].bluetooth);
+
+        let controllers = scenario.controllers_at(75.0).unwrap();
+        assert_eq!(controllers.len(), 2);
+        assert_eq!(controllers[0].capacity, 15);
+        assert_eq!(controllers[1].id(), "pro");
+        assert_eq!(controllers[1].capacity, 100);
+
+        let controllers = scenario.controllers_at(160.0).unwrap();
+        assert_eq!(controllers.len(), 1);
+        assert_eq!(controllers[0].name, "Pro Controller");
+
+        let controllers = scenario.controllers_at(190.0).unwrap();
+        assert_eq!(controllers[0].capacity, 10);
+        assert_eq!(controllers[0].status, Status::Charging);
+        assert!(!controllers[0].bluetooth);
+
+        assert!(scenario.controllers_at(205.0).is_err());
+        assert_eq!(scenario.controllers_at(215.0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_repeat_and_validate() {
+        let mut scenario: Scenario = serde_json::from_str(SCENARIO).unwrap();
+        scenario.repeat = true;
+        let controllers = scenario.controllers_at(210.0 + 75.0).unwrap();
+        assert_eq!(controllers[0].capacity, 15);
+
+        scenario.controllers[0].steps[1].at = -1.0;
+        assert!(scenario.validate().is_err());
+        scenario.controllers[0].steps[1].at = f64::NAN;
+        assert!(scenario.validate().is_err());
+        scenario.controllers[0].steps[1].at = 100.0;
+        scenario.controllers[0].steps[1].capacity = Some(101);
+        assert!(scenario.validate().is_err());
+        scenario.controllers[0].steps.clear();
+        assert!(scenario.validate().is_err());
+    }
+}
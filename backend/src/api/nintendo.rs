@@ -5,7 +5,7 @@ use serde::Deserialize;
 
 use crate::controller::Status;
 
-use super::Controller;
+use super::{BatteryInfo, Controller};
 
 pub const VENDOR_ID_NINTENDO: u16 = 0x057e;
 pub const PRODUCT_ID_NINTENDO_PROCON: u16 = 0x2009;
@@ -48,27 +48,36 @@ pub fn parse_controller_data(device_info: &DeviceInfo, hidapi: &HidApi) -> Resul
         }
     };
 
-    let input_report: InputReport = bincode::deserialize(&buf[0..3])?;
+    let battery_status = parse_report(&buf)?;
+    controller.capacity = battery_status.capacity;
+    controller.status = battery_status.status;
+
+    Ok(controller)
+}
+
+/// Decodes the battery from the header shared by all the input reports
+pub fn parse_report(report: &[u8]) -> Result<BatteryInfo> {
+    let input_report: InputReport = bincode::deserialize(report)?;
     let tmp = input_report.bat_con;
     let _host_powered = tmp & BIT!(0) != 0;
     let battery_charging = tmp & BIT!(4) != 0;
     let tmp = tmp >> 5;
-    controller.status = if battery_charging {
+    let status = if battery_charging {
         Status::Charging
     } else {
         Status::Discharging
     };
-    match tmp {
-        0 => controller.capacity = 5,
-        1 => controller.capacity = 25,
-        2 => controller.capacity = 50,
-        3 => controller.capacity = 75,
-        4 => controller.capacity = 100,
+    let capacity = match tmp {
+        0 => 5,
+        1 => 25,
+        2 => 50,
+        3 => 75,
+        4 => 100,
         _ => {
-            controller.capacity = 0;
             debug!("Unknown battery status: {}", tmp);
+            0
         }
-    }
+    };
 
-    Ok(controller)
+    Ok(BatteryInfo { capacity, status })
 }
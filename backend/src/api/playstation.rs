@@ -4,11 +4,11 @@ use anyhow::Result;
 use hidapi::{DeviceInfo, HidApi};
 use log::error;
 use log::info;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
 use crate::controller::Status;
 
-use super::Controller;
+use super::{BatteryInfo, Controller};
 
 pub const DS_VENDOR_ID: u16 = 0x054c;
 
@@ -144,38 +144,42 @@ struct DualShock4TouchPoint {
     y_hi: u8,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct BatteryInfo {
-    capacity: u8,
-    status: Status,
-}
-
 pub fn parse_dualshock_controller_data(
     device_info: &DeviceInfo,
     hidapi: &HidApi,
 ) -> Result<Controller> {
     let device = device_info.open_device(hidapi)?;
     let mut controller = Controller::from_hidapi(device_info, "DualShock 4", 0, Status::Unknown);
-    let mut buf = vec![0u8; DS4_INPUT_REPORT_BT_SIZE];
+    let mut buf = [0u8; DS4_INPUT_REPORT_BT_SIZE];
     let res = device.read(&mut buf[..])?;
+    let battery_status = parse_dualshock_report(&buf[..res], controller.bluetooth)?;
+    controller.capacity = battery_status.capacity;
+    controller.status = battery_status.status;
+    Ok(controller)
+}
+
+/// Decodes the battery from a DualShock 4 input report. Unhandled reports read as an empty
+/// battery, like the controller reports when it's not connected to a cable.
+pub fn parse_dualshock_report(report: &[u8], bluetooth: bool) -> Result<BatteryInfo> {
     let mut battery_data: u8 = 0;
     let mut cable_state: u8 = 0;
-    if !controller.bluetooth && buf[0] == DS4_INPUT_REPORT_USB && res == DS4_INPUT_REPORT_USB_SIZE {
-        let usb_report: Dualshock4InputReportUSB = bincode::deserialize(&buf)?;
+    let report_id = report.first().copied().unwrap_or_default();
+    if !bluetooth && report_id == DS4_INPUT_REPORT_USB && report.len() == DS4_INPUT_REPORT_USB_SIZE
+    {
+        let usb_report: Dualshock4InputReportUSB = bincode::deserialize(report)?;
         let ds4_report: DualShock4InputReportCommon = usb_report.common;
         battery_data = ds4_report.status[0] & DS4_STATUS_BATTERY_CAPACITY;
         cable_state = ds4_report.status[0] & DS4_STATUS0_CABLE_STATE;
-    } else if controller.bluetooth
-        && buf[0] == DS4_INPUT_REPORT_BT
-        && res == DS4_INPUT_REPORT_BT_SIZE
+    } else if bluetooth
+        && report_id == DS4_INPUT_REPORT_BT
+        && report.len() == DS4_INPUT_REPORT_BT_SIZE
     {
-        let bt_report: Dualshock4InputReportBT = bincode::deserialize(&buf)?;
+        let bt_report: Dualshock4InputReportBT = bincode::deserialize(report)?;
         let ds4_report: DualShock4InputReportCommon = bt_report.common;
         battery_data = ds4_report.status[0] & DS4_STATUS_BATTERY_CAPACITY;
         cable_state = ds4_report.status[0] & DS4_STATUS0_CABLE_STATE;
     } else {
-        error!("Unhandled report ID: {}", buf[0]);
+        error!("Unhandled report ID: {}", report_id);
     }
 
     let mut charging_status: u8 = 0x0;
@@ -188,10 +192,7 @@ pub fn parse_dualshock_controller_data(
             charging_status = 0xf;
         }
     }
-    let battery_status = get_battery_status(charging_status, battery_data);
-    controller.capacity = battery_status.capacity;
-    controller.status = battery_status.status;
-    Ok(controller)
+    Ok(get_battery_status(charging_status, battery_data))
 }
 
 pub fn parse_dualsense_controller_data(
@@ -206,24 +207,33 @@ pub fn parse_dualsense_controller_data(
     let mut buf = [0u8; DS_INPUT_REPORT_BT_SIZE];
     let res = device.read(&mut buf[..])?;
 
+    if let Some(battery_status) = parse_dualsense_report(&buf[..res], controller.bluetooth)? {
+        controller.capacity = battery_status.capacity;
+        controller.status = battery_status.status;
+    }
+
+    Ok(controller)
+}
+
+/// Decodes the battery from a DualSense input report, `None` if the report isn't handled
+pub fn parse_dualsense_report(report: &[u8], bluetooth: bool) -> Result<Option<BatteryInfo>> {
+    let report_id = report.first().copied().unwrap_or_default();
     let ds_report: DualSenseInputReport;
-    if !controller.bluetooth && buf[0] == DS_INPUT_REPORT_USB && res == DS_INPUT_REPORT_USB_SIZE {
-        ds_report = bincode::deserialize(&buf[1..])?;
-    } else if controller.bluetooth && buf[0] == DS_INPUT_REPORT_BT && res == DS_INPUT_REPORT_BT_SIZE
+    if !bluetooth && report_id == DS_INPUT_REPORT_USB && report.len() == DS_INPUT_REPORT_USB_SIZE {
+        ds_report = bincode::deserialize(&report[1..])?;
+    } else if bluetooth
+        && report_id == DS_INPUT_REPORT_BT
+        && report.len() == DS_INPUT_REPORT_BT_SIZE
     {
-        ds_report = bincode::deserialize(&buf[2..])?;
+        ds_report = bincode::deserialize(&report[2..])?;
     } else {
-        error!("Unhandled report ID: {}", buf[0]);
-        return Ok(controller);
+        error!("Unhandled report ID: {}", report_id);
+        return Ok(None);
     }
 
     let battery_data = ds_report.status & DS_STATUS_BATTERY_CAPACITY;
     let charging_status = (ds_report.status & DS_STATUS_CHARGING) >> DS_STATUS_CHARGING_SHIFT;
-    let battery_status = get_battery_status(charging_status, battery_data);
-    controller.capacity = battery_status.capacity;
-    controller.status = battery_status.status;
-
-    Ok(controller)
+    Ok(Some(get_battery_status(charging_status, battery_data)))
 }
 
 fn get_battery_status(charging_status: u8, battery_data: u8) -> BatteryInfo {
@@ -274,7 +284,21 @@ pub fn parse_dualshock3_controller_data(
         return Ok(controller);
     }
 
-    if buf[1] == 0xff {
+    if let Some(battery_status) = parse_dualshock3_report(&buf[..res]) {
+        controller.capacity = battery_status.capacity;
+        controller.status = battery_status.status;
+    }
+
+    Ok(controller)
+}
+
+/// Decodes the battery from a DualShock 3 input report, `None` if the report isn't handled
+pub fn parse_dualshock3_report(report: &[u8]) -> Option<BatteryInfo> {
+    if report.len() < 2 {
+        return None;
+    }
+
+    if report[1] == 0xff {
         /* Comment coppied from the linux driver at drivers/hid/hid-sony.c
          * When connected via Bluetooth the Sixaxis occasionally sends
          * a report with the second byte 0xff and the rest zeroed.
@@ -283,23 +307,20 @@ pub fn parse_dualshock3_controller_data(
          * controller must be ignored to avoid generating false input
          * events.
          */
-        return Ok(controller);
+        return None;
     }
 
+    #[allow(clippy::needless_late_init)]
     let battery_data: u8;
-    if buf[0] == DS3_INPUT_REPORT && res == DS3_INPUT_REPORT_SIZE
+    if report[0] == DS3_INPUT_REPORT && report.len() == DS3_INPUT_REPORT_SIZE
     {
-        battery_data = buf[DS3_INPUT_REPORT_BATTERY_OFFSET];
+        battery_data = report[DS3_INPUT_REPORT_BATTERY_OFFSET];
     } else {
-        error!("Unhandled report ID: {}", buf[0]);
-        return Ok(controller);
+        error!("Unhandled report ID: {}", report[0]);
+        return None;
     }
 
-    let battery_status = get_ds3_battery_status(battery_data);
-    controller.capacity = battery_status.capacity;
-    controller.status = battery_status.status;
-
-    Ok(controller)
+    Some(get_ds3_battery_status(battery_data))
 }
 
 fn get_ds3_battery_status(battery_data: u8) -> BatteryInfo {
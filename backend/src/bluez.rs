@@ -0,0 +1,477 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use zbus::{
+    fdo::{ObjectManager, ObjectManagerProxy},
+    interface,
+    message::Type,
+    proxy,
+    zvariant::{ObjectPath, OwnedObjectPath},
+    Connection, MatchRule, MessageStream,
+};
+
+use crate::{alerts::unix_now, api, controller::Controller, AppState};
+
+// How long a disconnect is remembered, the next check of the controllers comes well before
+const REQUEST_MEMORY: Duration = Duration::from_secs(5 * 60);
+// Reasons BlueZ gives when the link was lost rather than closed by either side
+const LOST_LINK_REASONS: [&str; 2] = ["org.bluez.Reason.Timeout", "org.bluez.Reason.Unknown"];
+// Root of the batteries we provide, BlueZ finds them through the object manager there
+const PROVIDER_PATH: &str = "/io/github/alphamercury/ControllerTools/battery";
+
+#[proxy(
+    interface = "org.bluez.BatteryProviderManager1",
+    default_service = "org.bluez"
+)]
+trait BatteryProviderManager {
+    fn register_battery_provider(&self, provider: &ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+/// Keeps track of the Bluetooth disconnects that were requested, by the host or by the device
+/// itself, e.g. when the user turns a controller off. A controller that runs out of battery just
+/// loses its link instead. Relies on the `Disconnected` signal of BlueZ 5.72 and later, with an
+/// older BlueZ or without a system bus no disconnect is ever requested.
+#[derive(Default, Clone)]
+pub struct DisconnectWatcher {
+    // Unix time of the last requested disconnect, keyed by lowercase Bluetooth address
+    requested: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl DisconnectWatcher {
+    /// Watches the disconnects on the system bus
+    pub async fn system() -> Self {
+        let watcher = Self::default();
+        let watching = match Connection::system().await {
+            Ok(connection) => watcher.watch(&connection).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = watching {
+            info!("Not watching Bluetooth disconnects: {:#}", err);
+        }
+        watcher
+    }
+
+    pub async fn watch(&self, connection: &Connection) -> Result<()> {
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface("org.bluez.Device1")?
+            .member("Disconnected")?
+            .build();
+        let mut messages = MessageStream::for_match_rule(rule, connection, None).await?;
+        let requested = self.requested.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = messages.next().await {
+                let header = message.header();
+                let Some(address) = header.path().and_then(|path| address(path.as_str())) else {
+                    continue;
+                };
+                let Ok((reason, _)) = message.body().deserialize::<(String, String)>() else {
+                    continue;
+                };
+                debug!("Bluetooth device {} disconnected: {}", address, reason);
+                if !LOST_LINK_REASONS.contains(&reason.as_str()) {
+                    requested.lock().unwrap().insert(address, unix_now());
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Whether the controller was disconnected on purpose lately
+    pub fn requested(&self, controller: &Controller) -> bool {
+        // hidraw gives Bluetooth devices their address as serial number
+        let Some(address) = controller
+            .serial_number
+            .as_ref()
+            .filter(|_| controller.bluetooth)
+        else {
+            return false;
+        };
+        let now = unix_now();
+        let mut requested = self.requested.lock().unwrap();
+        requested.retain(|_, time| *time + REQUEST_MEMORY.as_secs() > now);
+        requested.contains_key(&address.to_lowercase())
+    }
+}
+
+/// Provides BlueZ with the batteries of the Bluetooth controllers found by the monitor, so that
+/// desktops and Steam show the same percentage we do. Only batteries read from HID reports are
+/// provided, BlueZ knows the others already. Needs a system bus and a `bluetoothd` with its
+/// experimental features enabled, BlueZ still marks `BatteryProviderManager1` as experimental.
+pub async fn provide_batteries(state: Arc<AppState>) -> Result<()> {
+    let connection = match Connection::system().await {
+        Ok(connection) => connection,
+        Err(err) => {
+            info!("Not providing batteries to BlueZ: {:#}", err);
+            return Ok(());
+        }
+    };
+    let mut provider = BatteryProvider::new(&connection).await?;
+    let mut controllers = state.controllers.subscribe();
+    while controllers.changed().await.is_ok() {
+        let current = controllers.borrow_and_update().clone();
+        if let Err(err) = provider.update(&current).await {
+            warn!(
+                "Failed to update the batteries provided to BlueZ: {:#}",
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A battery provided to BlueZ
+struct Battery {
+    percentage: u8,
+    device: OwnedObjectPath,
+}
+
+#[interface(name = "org.bluez.BatteryProvider1")]
+impl Battery {
+    #[zbus(property)]
+    fn percentage(&self) -> u8 {
+        self.percentage
+    }
+
+    #[zbus(property)]
+    fn device(&self) -> OwnedObjectPath {
+        self.device.clone()
+    }
+
+    #[zbus(property)]
+    fn source(&self) -> &str {
+        "HID"
+    }
+}
+
+pub struct BatteryProvider {
+    connection: Connection,
+    // Adapters we registered with, or tried to
+    adapters: HashSet<OwnedObjectPath>,
+    // Percentage of each battery we provide, by lowercase Bluetooth address
+    batteries: HashMap<String, u8>,
+}
+
+impl BatteryProvider {
+    pub async fn new(connection: &Connection) -> Result<Self> {
+        connection
+            .object_server()
+            .at(PROVIDER_PATH, ObjectManager)
+            .await?;
+        Ok(Self {
+            connection: connection.clone(),
+            adapters: HashSet::new(),
+            batteries: HashMap::new(),
+        })
+    }
+
+    /// Provides the batteries of these controllers, and stops providing the others
+    pub async fn update(&mut self, controllers: &[Controller]) -> Result<()> {
+        // hidraw gives Bluetooth devices their address as serial number
+        let wanted: HashMap<String, u8> = controllers
+            .iter()
+            .filter(|controller| controller.bluetooth && api::hid_battery(controller.vendor_id))
+            .filter_map(|controller| {
+                let address = controller.serial_number.as_ref()?.to_lowercase();
+                Some((address, controller.capacity))
+            })
+            .collect();
+
+        let connection = self.connection.clone();
+        let object_server = connection.object_server();
+        let gone: Vec<String> = self
+            .batteries
+            .keys()
+            .filter(|address| !wanted.contains_key(*address))
+            .cloned()
+            .collect();
+        for address in gone {
+            object_server
+                .remove::<Battery, _>(battery_path(&address))
+                .await?;
+            self.batteries.remove(&address);
+        }
+
+        for (address, percentage) in wanted {
+            match self.batteries.get(&address) {
+                Some(provided) if *provided == percentage => {}
+                Some(_) => {
+                    let battery = object_server
+                        .interface::<_, Battery>(battery_path(&address))
+                        .await?;
+                    battery.get_mut().await.percentage = percentage;
+                    battery
+                        .get()
+                        .await
+                        .percentage_changed(battery.signal_emitter())
+                        .await?;
+                    self.batteries.insert(address, percentage);
+                }
+                None => {
+                    let Some((device, adapter)) = self.device(&address).await? else {
+                        debug!("BlueZ doesn't know {}", address);
+                        continue;
+                    };
+                    let battery = Battery { percentage, device };
+                    object_server.at(battery_path(&address), battery).await?;
+                    self.batteries.insert(address, percentage);
+                    self.register(adapter).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of the BlueZ device with this address, and of its adapter
+    async fn device(&self, address: &str) -> Result<Option<(OwnedObjectPath, OwnedObjectPath)>> {
+        let bluez = ObjectManagerProxy::builder(&self.connection)
+            .destination("org.bluez")?
+            .path("/")?
+            .build()
+            .await?;
+        for (path, interfaces) in bluez.get_managed_objects().await? {
+            let Some(device) = interfaces.get("org.bluez.Device1") else {
+                continue;
+            };
+            let matches = device
+                .get("Address")
+                .and_then(|value| <&str>::try_from(value).ok())
+                .is_some_and(|device_address| device_address.eq_ignore_ascii_case(address));
+            let adapter = device
+                .get("Adapter")
+                .and_then(|value| OwnedObjectPath::try_from(value.clone()).ok());
+            if let (true, Some(adapter)) = (matches, adapter) {
+                return Ok(Some((path, adapter)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn register(&mut self, adapter: OwnedObjectPath) -> Result<()> {
+        if !self.adapters.insert(adapter.clone()) {
+            return Ok(());
+        }
+        let manager = BatteryProviderManagerProxy::builder(&self.connection)
+            .path(adapter.clone())?
+            .build()
+            .await?;
+        match manager
+            .register_battery_provider(&ObjectPath::from_static_str_unchecked(PROVIDER_PATH))
+            .await
+        {
+            Ok(()) => info!("Providing batteries to BlueZ for {}", adapter.as_str()),
+            // Most likely bluetoothd runs without its experimental features
+            Err(err) => info!(
+                "BlueZ doesn't take batteries for {}: {}",
+                adapter.as_str(),
+                err
+            ),
+        }
+        Ok(())
+    }
+}
+
+fn battery_path(address: &str) -> String {
+    format!(
+        "{}/dev_{}",
+        PROVIDER_PATH,
+        address.replace(':', "_").to_uppercase()
+    )
+}
+
+/// `aa:bb:cc:dd:ee:ff` from a BlueZ device path like `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF`
+fn address(path: &str) -> Option<String> {
+    let device = path.rsplit('/').next()?.strip_prefix("dev_")?;
+    Some(device.replace('_', ":").to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use zbus::{
+        connection,
+        fdo::{ObjectManager, ObjectManagerProxy},
+        interface,
+        zvariant::{ObjectPath, OwnedObjectPath},
+        Connection, Guid,
+    };
+
+    use super::{address, BatteryProvider, DisconnectWatcher, PROVIDER_PATH};
+    use crate::controller::{Controller, Status};
+
+    const ADAPTER_PATH: &str = "/org/bluez/hci0";
+
+    struct StubDevice {
+        address: String,
+    }
+
+    #[interface(name = "org.bluez.Device1")]
+    impl StubDevice {
+        #[zbus(property)]
+        fn address(&self) -> &str {
+            &self.address
+        }
+
+        #[zbus(property)]
+        fn adapter(&self) -> ObjectPath<'_> {
+            ObjectPath::from_static_str_unchecked(ADAPTER_PATH)
+        }
+    }
+
+    #[derive(Default)]
+    struct StubManager {
+        providers: Arc<Mutex<Vec<OwnedObjectPath>>>,
+    }
+
+    #[interface(name = "org.bluez.BatteryProviderManager1")]
+    impl StubManager {
+        fn register_battery_provider(&self, provider: OwnedObjectPath) {
+            self.providers.lock().unwrap().push(provider);
+        }
+    }
+
+    /// A peer to peer connection to a stub BlueZ, which knows a single device
+    async fn bluez(manager: StubManager) -> anyhow::Result<(Connection, Connection)> {
+        let device = StubDevice {
+            address: "A0:AB:51:12:34:56".to_string(),
+        };
+        let (bluez, client) = tokio::net::UnixStream::pair()?;
+        let bluez = connection::Builder::unix_stream(bluez)
+            .server(Guid::generate())?
+            .p2p()
+            .serve_at("/", ObjectManager)?
+            .serve_at(format!("{}/dev_A0_AB_51_12_34_56", ADAPTER_PATH), device)?
+            .serve_at(ADAPTER_PATH, manager)?
+            .build();
+        let client = connection::Builder::unix_stream(client).p2p().build();
+        Ok(tokio::try_join!(bluez, client)?)
+    }
+
+    fn controller(address: &str) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity: 5,
+            status: Status::Discharging,
+            bluetooth: true,
+            serial_number: Some(address.to_string()),
+            device_path: Some("/dev/hidraw1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(
+            address("/org/bluez/hci0/dev_A0_AB_51_12_34_56").as_deref(),
+            Some("a0:ab:51:12:34:56")
+        );
+        assert_eq!(address("/org/bluez/hci0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_watcher() -> anyhow::Result<()> {
+        // A peer to peer connection stands in for the system bus
+        let (bluez, client) = tokio::net::UnixStream::pair()?;
+        let bluez = connection::Builder::unix_stream(bluez)
+            .server(Guid::generate())?
+            .p2p()
+            .build();
+        let client = connection::Builder::unix_stream(client).p2p().build();
+        let (bluez, client) = tokio::try_join!(bluez, client)?;
+
+        let watcher = DisconnectWatcher::default();
+        watcher.watch(&client).await?;
+        for (path, reason) in [
+            (
+                "/org/bluez/hci0/dev_A0_AB_51_12_34_56",
+                "org.bluez.Reason.Remote",
+            ),
+            (
+                "/org/bluez/hci0/dev_A0_AB_51_65_43_21",
+                "org.bluez.Reason.Timeout",
+            ),
+        ] {
+            bluez
+                .emit_signal(
+                    None::<()>,
+                    path,
+                    "org.bluez.Device1",
+                    "Disconnected",
+                    &(reason, "Disconnected"),
+                )
+                .await?;
+        }
+
+        let turned_off = controller("a0:ab:51:12:34:56");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !watcher.requested(&turned_off) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert!(!watcher.requested(&controller("a0:ab:51:65:43:21")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_battery_provider() -> anyhow::Result<()> {
+        let manager = StubManager::default();
+        let providers = manager.providers.clone();
+        let (bluez, client) = bluez(manager).await?;
+        // What BlueZ sees of the batteries we provide
+        let batteries = ObjectManagerProxy::builder(&bluez)
+            .destination("org.example.ControllerTools")?
+            .path(PROVIDER_PATH)?
+            .build()
+            .await?;
+
+        let mut provider = BatteryProvider::new(&client).await?;
+        let mut xbox = controller("a0:ab:51:12:34:56");
+        xbox.vendor_id = 0x045e;
+        let unknown = controller("a0:ab:51:65:43:21");
+        provider.update(&[xbox.clone(), unknown.clone()]).await?;
+        assert!(batteries.get_managed_objects().await?.is_empty());
+
+        let mut dualsense = controller("a0:ab:51:12:34:56");
+        provider
+            .update(&[dualsense.clone(), unknown.clone()])
+            .await?;
+        dualsense.capacity = 30;
+        provider.update(&[dualsense, unknown.clone()]).await?;
+        let registered: Vec<_> = providers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(registered, [PROVIDER_PATH]);
+        let objects = batteries.get_managed_objects().await?;
+        assert_eq!(objects.len(), 1);
+        let (path, interfaces) = objects.into_iter().next().unwrap();
+        assert_eq!(
+            path.as_str(),
+            format!("{}/dev_A0_AB_51_12_34_56", PROVIDER_PATH)
+        );
+        let battery = &interfaces["org.bluez.BatteryProvider1"];
+        assert_eq!(u8::try_from(&battery["Percentage"])?, 30);
+        assert_eq!(
+            OwnedObjectPath::try_from(battery["Device"].try_clone()?)?.as_str(),
+            "/org/bluez/hci0/dev_A0_AB_51_12_34_56"
+        );
+
+        provider.update(&[unknown]).await?;
+        assert!(batteries.get_managed_objects().await?.is_empty());
+        Ok(())
+    }
+}
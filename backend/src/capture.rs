@@ -0,0 +1,400 @@
+//! Raw HID captures, attached to bug reports when a controller shows the wrong battery.
+//!
+//! A capture is a JSON file with camelCase keys:
+//!
+//! - `format`: version of this layout, currently 1
+//! - `toolVersion`: version of the backend that made the capture
+//! - `capturedAt`: when the capture started, in milliseconds since the Unix epoch
+//! - `device`: what hidapi knows about the device (`path`, `vendorId`, `productId`,
+//!   `serialNumber`, `manufacturer`, `product`, `interfaceNumber`, `usagePage`, `usage` and
+//!   `bluetooth`, which is how the parsers tell the USB and Bluetooth reports apart)
+//! - `reportDescriptor`: the HID report descriptor as a hex string, empty if it couldn't be read
+//! - `featureReports`: one entry per report ID declared by a Feature item of the descriptor
+//! - `inputReports`: the input reports in the order the device sent them
+//!
+//! Each report is `{"elapsedMs": <milliseconds since capturedAt>, "data": "<hex>"}`. The data
+//! starts with the report ID when the device uses them, exactly as hidapi returns it.
+
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use hidapi::{DeviceInfo, HidApi, MAX_REPORT_DESCRIPTOR_SIZE};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::api;
+
+pub const CAPTURE_FORMAT: u32 = 1;
+
+// Large enough for the biggest feature report a hidraw device can return
+const FEATURE_REPORT_SIZE: usize = 4096;
+// Large enough for every input report the parsers know about, the Nintendo ones are the biggest
+const INPUT_REPORT_SIZE: usize = 512;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capture {
+    pub format: u32,
+    pub tool_version: String,
+    pub captured_at: u64,
+    pub device: CapturedDevice,
+    #[serde(with = "hex")]
+    pub report_descriptor: Vec<u8>,
+    pub feature_reports: Vec<CapturedReport>,
+    pub input_reports: Vec<CapturedReport>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedDevice {
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub interface_number: i32,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub bluetooth: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedReport {
+    pub elapsed_ms: u64,
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
+}
+
+impl From<&DeviceInfo> for CapturedDevice {
+    fn from(device_info: &DeviceInfo) -> Self {
+        Self {
+            path: device_info.path().to_string_lossy().to_string(),
+            vendor_id: device_info.vendor_id(),
+            product_id: device_info.product_id(),
+            serial_number: device_info.serial_number().map(str::to_string),
+            manufacturer: device_info.manufacturer_string().map(str::to_string),
+            product: device_info.product_string().map(str::to_string),
+            interface_number: device_info.interface_number(),
+            usage_page: device_info.usage_page(),
+            usage: device_info.usage(),
+            // Same rule as `Controller::from_hidapi`
+            bluetooth: device_info.interface_number() == -1,
+        }
+    }
+}
+
+/// Records `input_reports` input reports from the device matching `selector`, which is either a
+/// `vendor:product` pair in hex like lsusb prints it (e.g. `054c:0ce6`), a hidraw path or a
+/// serial number. Stops early if the device doesn't send anything for `timeout`.
+pub fn record(selector: &str, input_reports: usize, timeout: Duration) -> Result<Capture> {
+    // hidapi takes the timeout in milliseconds as an i32
+    let timeout_ms = i32::try_from(timeout.as_millis())
+        .map_err(|_| anyhow!("Timeout of {:?} is too long", timeout))?;
+    let hidapi = HidApi::new()?;
+    let device_info = find_device(&hidapi, selector)?;
+    let device = device_info
+        .open_device(&hidapi)
+        .with_context(|| format!("Failed to open {}", device_info.path().to_string_lossy()))?;
+    let started = Instant::now();
+    let captured_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    let elapsed_ms = || started.elapsed().as_millis() as u64;
+
+    let mut buf = vec![0u8; MAX_REPORT_DESCRIPTOR_SIZE];
+    let report_descriptor = match device.get_report_descriptor(&mut buf) {
+        Ok(size) => buf[..size].to_vec(),
+        Err(err) => {
+            warn!("Failed to read the report descriptor: {}", err);
+            Vec::new()
+        }
+    };
+
+    let mut feature_reports = Vec::new();
+    for report_id in feature_report_ids(&report_descriptor) {
+        let mut buf = vec![0u8; FEATURE_REPORT_SIZE];
+        buf[0] = report_id;
+        match device.get_feature_report(&mut buf) {
+            Ok(size) => feature_reports.push(CapturedReport {
+                elapsed_ms: elapsed_ms(),
+                data: buf[..size].to_vec(),
+            }),
+            Err(err) => warn!("Failed to read feature report {:#04x}: {}", report_id, err),
+        }
+    }
+
+    let mut reports = Vec::with_capacity(input_reports);
+    let mut buf = [0u8; INPUT_REPORT_SIZE];
+    while reports.len() < input_reports {
+        let size = device.read_timeout(&mut buf, timeout_ms)?;
+        if size == 0 {
+            warn!(
+                "No input report for {:?}, stopping after {} reports",
+                timeout,
+                reports.len()
+            );
+            break;
+        }
+        reports.push(CapturedReport {
+            elapsed_ms: elapsed_ms(),
+            data: buf[..size].to_vec(),
+        });
+    }
+
+    Ok(Capture {
+        format: CAPTURE_FORMAT,
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        captured_at,
+        device: device_info.into(),
+        report_descriptor,
+        feature_reports,
+        input_reports: reports,
+    })
+}
+
+/// Runs every input report of the capture through the parser `api::controllers` uses for the
+/// device and describes what it decoded, one line per report.
+pub fn decode(capture: &Capture) -> Vec<String> {
+    let device = &capture.device;
+    capture
+        .input_reports
+        .iter()
+        .enumerate()
+        .map(|(index, report)| {
+            let decoded = match api::parse_report(
+                device.vendor_id,
+                device.product_id,
+                device.bluetooth,
+                &report.data,
+            ) {
+                Ok(Some(battery)) => {
+                    format!("{}% {:?}", battery.capacity, battery.status).to_lowercase()
+                }
+                Ok(None) => "ignored by the parser".to_string(),
+                Err(err) => format!("{:#}", err),
+            };
+            format!(
+                "#{:<3} +{}ms  id {:#04x}  {} bytes  {}",
+                index,
+                report.elapsed_ms,
+                report.data.first().copied().unwrap_or_default(),
+                report.data.len(),
+                decoded
+            )
+        })
+        .collect()
+}
+
+fn find_device<'a>(hidapi: &'a HidApi, selector: &str) -> Result<&'a DeviceInfo> {
+    let vendor_product = parse_vendor_product(selector);
+    let matches: Vec<_> = hidapi
+        .device_list()
+        .filter(|device_info| {
+            Some((device_info.vendor_id(), device_info.product_id())) == vendor_product
+                || device_info.path().to_string_lossy() == selector
+                || device_info.serial_number() == Some(selector)
+        })
+        .collect();
+
+    let device_info = matches
+        .first()
+        .ok_or_else(|| anyhow!("No HID device matches {}", selector))?;
+    if matches.len() > 1 {
+        info!(
+            "{} devices match {}, capturing {}. Pass its path to pick another one.",
+            matches.len(),
+            selector,
+            device_info.path().to_string_lossy()
+        );
+    }
+    Ok(device_info)
+}
+
+/// Parses a `vendor:product` pair of 4 digit hex ids, e.g. `054c:0ce6`
+fn parse_vendor_product(selector: &str) -> Option<(u16, u16)> {
+    let (vendor, product) = selector.split_once(':')?;
+    let parse = |id: &str| {
+        // from_str_radix also takes a sign, e.g. "+54c"
+        if id.len() != 4 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        u16::from_str_radix(id, 16).ok()
+    };
+    Some((parse(vendor)?, parse(product)?))
+}
+
+/// Walks the short items of a report descriptor and returns the report IDs used by its Feature
+/// items. Devices that don't use report IDs get report 0.
+fn feature_report_ids(descriptor: &[u8]) -> BTreeSet<u8> {
+    const ITEM_FEATURE: u8 = 0xb0;
+    const ITEM_REPORT_ID: u8 = 0x84;
+    const ITEM_PUSH: u8 = 0xa4;
+    const ITEM_POP: u8 = 0xb4;
+    const ITEM_LONG: u8 = 0xfe;
+
+    let mut ids = BTreeSet::new();
+    let mut report_id = 0u8;
+    let mut stack = Vec::new();
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        if prefix == ITEM_LONG {
+            // Long items carry their data size in the next byte, followed by their tag
+            let size = descriptor.get(i + 1).copied().unwrap_or_default() as usize;
+            i += 3 + size;
+            continue;
+        }
+
+        let size = match prefix & 0b11 {
+            3 => 4,
+            size => size as usize,
+        };
+        let data = descriptor.get(i + 1..i + 1 + size).unwrap_or_default();
+        match prefix & !0b11 {
+            ITEM_REPORT_ID => report_id = data.first().copied().unwrap_or_default(),
+            ITEM_PUSH => stack.push(report_id),
+            ITEM_POP => report_id = stack.pop().unwrap_or_default(),
+            ITEM_FEATURE => {
+                ids.insert(report_id);
+            }
+            _ => {}
+        }
+        i += 1 + size;
+    }
+    ids
+}
+
+/// Bytes as a lowercase hex string, which keeps the captures readable and easy to diff
+mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("hex string has an odd length"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| D::Error::custom(format!("invalid hex at {}", i)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode, feature_report_ids, parse_vendor_product, Capture, CapturedDevice, CapturedReport,
+    };
+
+    #[test]
+    fn test_parse_vendor_product() {
+        assert_eq!(parse_vendor_product("054c:0ce6"), Some((0x054c, 0x0ce6)));
+        assert_eq!(parse_vendor_product("057E:2009"), Some((0x057e, 0x2009)));
+        // Always hex like lsusb, this isn't the decimal spelling of 054c:0ce6
+        assert_eq!(parse_vendor_product("1356:3302"), Some((0x1356, 0x3302)));
+        assert_eq!(parse_vendor_product("54c:ce6"), None);
+        assert_eq!(parse_vendor_product("+54c:0ce6"), None);
+        assert_eq!(parse_vendor_product("1356:33020"), None);
+        assert_eq!(parse_vendor_product("/dev/hidraw3"), None);
+    }
+
+    #[test]
+    fn test_feature_report_ids() {
+        // Usage Page (Generic Desktop), Usage (Game Pad), Collection (Application),
+        // Report ID (0x01), Input, Report ID (0x05), Feature, Report ID (0x20), Feature,
+        // End Collection
+        let descriptor = [
+            0x05, 0x01, 0x09, 0x05, 0xa1, 0x01, 0x85, 0x01, 0x81, 0x02, 0x85, 0x05, 0xb1, 0x02,
+            0x85, 0x20, 0xb1, 0x02, 0xc0,
+        ];
+        assert_eq!(
+            feature_report_ids(&descriptor)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![0x05, 0x20]
+        );
+
+        // No report IDs, a single Feature item
+        let descriptor = [0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0xb1, 0x02, 0xc0];
+        assert_eq!(
+            feature_report_ids(&descriptor)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+
+        assert!(feature_report_ids(&[0x05, 0x01, 0x81]).is_empty());
+    }
+
+    #[test]
+    fn test_capture_file() {
+        // DualSense over USB, the status byte says 15% and discharging
+        let mut report = vec![0u8; 64];
+        report[0] = 0x01;
+        report[53] = 0x01;
+        let capture = Capture {
+            format: 1,
+            tool_version: "2.0.2".to_string(),
+            captured_at: 1_700_000_000_000,
+            device: CapturedDevice {
+                path: "/dev/hidraw3".to_string(),
+                vendor_id: 0x054c,
+                product_id: 0x0ce6,
+                serial_number: None,
+                manufacturer: Some("Sony Interactive Entertainment".to_string()),
+                product: Some("DualSense Wireless Controller".to_string()),
+                interface_number: 3,
+                usage_page: 1,
+                usage: 5,
+                bluetooth: false,
+            },
+            report_descriptor: vec![0x05, 0x01],
+            feature_reports: vec![CapturedReport {
+                elapsed_ms: 1,
+                data: vec![0x05, 0xab],
+            }],
+            input_reports: vec![
+                CapturedReport {
+                    elapsed_ms: 5,
+                    data: report,
+                },
+                CapturedReport {
+                    elapsed_ms: 9,
+                    data: vec![0x31, 0x00],
+                },
+            ],
+        };
+
+        let json = serde_json::to_value(&capture).unwrap();
+        assert_eq!(json["reportDescriptor"], "0501");
+        assert_eq!(json["featureReports"][0]["data"], "05ab");
+        assert_eq!(json["device"]["vendorId"], 0x054c);
+
+        let capture: Capture = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            decode(&capture),
+            vec![
+                "#0   +5ms  id 0x01  64 bytes  15% discharging",
+                "#1   +9ms  id 0x31  2 bytes  ignored by the parser",
+            ]
+        );
+
+        let invalid = r#"{"elapsedMs": 0, "data": "0g"}"#;
+        assert!(serde_json::from_str::<CapturedReport>(invalid).is_err());
+    }
+}
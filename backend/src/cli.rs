@@ -0,0 +1,470 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    io::IsTerminal,
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use simplelog::LevelFilter;
+
+use crate::{
+    api, capture,
+    controller::{Controller, Status},
+    dbus::Bus,
+    PORT,
+};
+
+// Used when no settings file is given, e.g. when running outside Decky
+const DEFAULT_SETTINGS_PATH: &str = "/tmp/controller-tools.json";
+const DEFAULT_DATA_DIR: &str = "/tmp/controller-tools";
+
+/// Battery level and charging status of game controllers
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Settings file, created with the default settings if it doesn't exist
+    #[arg(long, global = true, value_name = "FILE", default_value = DEFAULT_SETTINGS_PATH)]
+    pub settings: PathBuf,
+
+    /// Where to keep the battery history
+    #[arg(long, global = true, value_name = "DIR", default_value = DEFAULT_DATA_DIR)]
+    pub data_dir: PathBuf,
+
+    /// Also write the logs to this file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// One of off, error, warn, info, debug or trace. Overrides the `debug` setting.
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Add the fake controllers of this scenario file, see `api/fake.rs` for the format
+    #[arg(long, global = true, value_name = "FILE")]
+    pub scenario: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP and WebSocket API (the default)
+    Serve(ServeArgs),
+    /// Print the connected controllers and exit
+    List(ListArgs),
+    /// Print the connected controllers every few seconds
+    Watch(WatchArgs),
+    /// Record the raw HID reports of a controller for a bug report
+    Capture(CaptureArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Port to listen on
+    #[arg(long, default_value_t = PORT)]
+    pub port: u16,
+
+    /// Address to listen on
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+
+    /// Also serve the controllers on D-Bus, as `io.github.alphamercury.ControllerTools` on the
+    /// session or system bus
+    #[arg(long, value_name = "BUS")]
+    pub dbus: Option<Bus>,
+}
+
+impl Default for ServeArgs {
+    fn default() -> Self {
+        Self {
+            port: PORT,
+            bind: IpAddr::from([127, 0, 0, 1]),
+            dbus: None,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    /// Print JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// Seconds between two checks
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
+
+    /// Print the controllers as JSON, one line per check
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CaptureArgs {
+    /// Device to record: vendor:product in hex (e.g. 054c:0ce6), hidraw path or serial number
+    #[arg(required_unless_present = "replay")]
+    pub device: Option<String>,
+
+    /// Number of input reports to record
+    #[arg(long, default_value_t = 20)]
+    pub reports: usize,
+
+    /// Stop recording if the device sends nothing for this many milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    pub timeout: u64,
+
+    /// Where to write the capture
+    #[arg(long, value_name = "FILE", default_value = "controller-capture.json")]
+    pub output: PathBuf,
+
+    /// Decode an existing capture instead of recording one
+    #[arg(long, value_name = "FILE", conflicts_with = "device")]
+    pub replay: Option<PathBuf>,
+}
+
+// Number of transitions kept below the table when refreshing in place
+const WATCH_HISTORY: usize = 10;
+
+/// Prints the connected controllers as a table or as JSON
+pub async fn list(args: &ListArgs) -> Result<()> {
+    let controllers = api::controllers_async().await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&controllers)?);
+    } else {
+        print!("{}", format_table(&controllers));
+    }
+    Ok(())
+}
+
+/// Checks the controllers every `interval` seconds until interrupted. On a terminal the table is
+/// redrawn in place with the latest transitions below it, otherwise only the transitions are
+/// printed after the first table so the output can be piped to a file.
+pub async fn watch(args: &WatchArgs) -> Result<()> {
+    let refresh_in_place = std::io::stdout().is_terminal();
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_secs(args.interval));
+    let mut previous: Option<Vec<Controller>> = None;
+    let mut history = VecDeque::with_capacity(WATCH_HISTORY);
+
+    loop {
+        interval.tick().await;
+        let controllers = api::controllers_async().await?;
+        if args.json {
+            println!("{}", serde_json::to_string(&controllers)?);
+            continue;
+        }
+
+        let lines = match &previous {
+            Some(previous) => transitions(previous, &controllers),
+            None => Vec::new(),
+        };
+        let elapsed = format_elapsed(started.elapsed());
+        if refresh_in_place {
+            for line in lines {
+                if history.len() == WATCH_HISTORY {
+                    history.pop_front();
+                }
+                history.push_back(format!("[{}] {}", elapsed, line));
+            }
+            // Clear the screen and move the cursor back to the top left corner
+            print!("\x1b[2J\x1b[H");
+            println!("Every {}s, elapsed {}\n", args.interval, elapsed);
+            print!("{}", format_table(&controllers));
+            if !history.is_empty() {
+                println!();
+                history.iter().for_each(|line| println!("{}", line));
+            }
+        } else if previous.is_none() {
+            print!("{}", format_table(&controllers));
+        } else {
+            lines
+                .iter()
+                .for_each(|line| println!("[{}] {}", elapsed, line));
+        }
+        previous = Some(controllers);
+    }
+}
+
+/// Records a capture and prints what the parsers make of it, or only decodes the file given with
+/// `--replay`
+pub async fn capture(args: &CaptureArgs) -> Result<()> {
+    let capture = match (&args.replay, &args.device) {
+        (Some(replay), _) => {
+            let contents = tokio::fs::read(replay)
+                .await
+                .with_context(|| format!("Failed to read {}", replay.display()))?;
+            serde_json::from_slice(&contents)
+                .with_context(|| format!("Failed to parse {}", replay.display()))?
+        }
+        (None, Some(device)) => {
+            let device = device.clone();
+            let (reports, timeout) = (args.reports, Duration::from_millis(args.timeout));
+            let capture =
+                tokio::task::spawn_blocking(move || capture::record(&device, reports, timeout))
+                    .await??;
+            tokio::fs::write(&args.output, serde_json::to_vec_pretty(&capture)?)
+                .await
+                .with_context(|| format!("Failed to write {}", args.output.display()))?;
+            println!(
+                "Recorded {} input and {} feature reports to {}",
+                capture.input_reports.len(),
+                capture.feature_reports.len(),
+                args.output.display()
+            );
+            capture
+        }
+        (None, None) => unreachable!("clap requires a device unless --replay is given"),
+    };
+
+    let device = &capture.device;
+    println!(
+        "{} {:04x}:{:04x} over {}",
+        device.product.as_deref().unwrap_or("Unknown device"),
+        device.vendor_id,
+        device.product_id,
+        if device.bluetooth { "Bluetooth" } else { "USB" }
+    );
+    capture::decode(&capture)
+        .iter()
+        .for_each(|line| println!("{}", line));
+    Ok(())
+}
+
+fn format_table(controllers: &[Controller]) -> String {
+    if controllers.is_empty() {
+        return "No controllers connected\n".to_string();
+    }
+
+    let headers = ["NAME", "BATTERY", "STATUS", "CONNECTION", "VID:PID"];
+    let rows: Vec<[String; 5]> = controllers
+        .iter()
+        .map(|controller| {
+            [
+                controller.name.clone(),
+                format!("{}%", controller.capacity),
+                status_name(controller.status).to_string(),
+                connection_name(controller).to_string(),
+                format!("{:04x}:{:04x}", controller.vendor_id, controller.product_id),
+            ]
+        })
+        .collect();
+
+    let mut widths = headers.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    let headers = headers.map(str::to_string);
+    for row in std::iter::once(&headers).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell))
+            .collect::<Vec<_>>()
+            .join("  ");
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+    table
+}
+
+/// Describes what changed between two checks: controllers connecting or disconnecting, their
+/// charging status changing, or the connection switching between USB and Bluetooth. Controllers
+/// are matched on their stable id, their hidraw device changes along with the connection.
+fn transitions(previous: &[Controller], current: &[Controller]) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for controller in current {
+        let id = controller.stable_id();
+        match previous.iter().find(|p| p.stable_id() == id) {
+            None => lines.push(format!(
+                "{} connected over {} ({}%, {})",
+                controller.name,
+                connection_name(controller),
+                controller.capacity,
+                status_name(controller.status)
+            )),
+            Some(previous) => {
+                if previous.status != controller.status {
+                    lines.push(format!(
+                        "{}: {} → {} ({}%)",
+                        controller.name,
+                        status_name(previous.status),
+                        status_name(controller.status),
+                        controller.capacity
+                    ));
+                }
+                if previous.bluetooth != controller.bluetooth {
+                    lines.push(format!(
+                        "{}: {} → {}",
+                        controller.name,
+                        connection_name(previous),
+                        connection_name(controller)
+                    ));
+                }
+            }
+        }
+    }
+
+    for controller in previous {
+        let id = controller.stable_id();
+        if !current.iter().any(|c| c.stable_id() == id) {
+            lines.push(format!(
+                "{} disconnected ({}%)",
+                controller.name, controller.capacity
+            ));
+        }
+    }
+
+    lines
+}
+
+fn status_name(status: Status) -> &'static str {
+    match status {
+        Status::Charging => "charging",
+        Status::Discharging => "discharging",
+        Status::Unknown => "unknown",
+    }
+}
+
+fn connection_name(controller: &Controller) -> &'static str {
+    if controller.bluetooth {
+        "Bluetooth"
+    } else {
+        "USB"
+    }
+}
+
+fn format_elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use simplelog::LevelFilter;
+
+    use crate::controller::{Controller, Status};
+
+    use super::{format_table, transitions, Cli, Command};
+
+    fn controller(name: &str, path: &str, capacity: u8, status: Status) -> Controller {
+        Controller {
+            name: name.to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity,
+            status,
+            bluetooth: true,
+            serial_number: None,
+            device_path: Some(path.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let cli = Cli::try_parse_from(["controller-tools"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.log_file.is_none());
+
+        let cli = Cli::try_parse_from([
+            "controller-tools",
+            "serve",
+            "--settings",
+            "/home/deck/settings.json",
+            "--port",
+            "8080",
+            "--bind",
+            "0.0.0.0",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(cli.settings.to_str(), Some("/home/deck/settings.json"));
+        assert_eq!(cli.log_level, Some(LevelFilter::Debug));
+        match cli.command {
+            Some(Command::Serve(args)) => {
+                assert_eq!(args.port, 8080);
+                assert_eq!(args.bind.to_string(), "0.0.0.0");
+            }
+            command => panic!("Unexpected command {:?}", command),
+        }
+
+        assert!(Cli::try_parse_from(["controller-tools", "serve", "--port", "x"]).is_err());
+        assert!(Cli::try_parse_from(["controller-tools", "watch", "--interval", "0"]).is_err());
+        assert!(Cli::try_parse_from(["controller-tools", "/tmp", "/tmp/log"]).is_err());
+
+        assert!(Cli::try_parse_from(["controller-tools", "capture"]).is_err());
+        let cli = Cli::try_parse_from(["controller-tools", "capture", "054c:0ce6"]).unwrap();
+        match cli.command {
+            Some(Command::Capture(args)) => {
+                assert_eq!(args.device.as_deref(), Some("054c:0ce6"));
+                assert_eq!(args.reports, 20);
+            }
+            command => panic!("Unexpected command {:?}", command),
+        }
+        let args = ["controller-tools", "capture", "--replay", "capture.json"];
+        assert!(Cli::try_parse_from(args).is_ok());
+    }
+
+    #[test]
+    fn test_format_table() {
+        assert_eq!(format_table(&[]), "No controllers connected\n");
+
+        let controllers = vec![
+            controller("DualSense", "/dev/hidraw0", 15, Status::Discharging),
+            controller("Xbox", "/dev/hidraw1", 100, Status::Charging),
+        ];
+        assert_eq!(
+            format_table(&controllers),
+            "NAME       BATTERY  STATUS       CONNECTION  VID:PID\n\
+             DualSense  15%      discharging  Bluetooth   054c:0ce6\n\
+             Xbox       100%     charging     Bluetooth   054c:0ce6\n"
+        );
+    }
+
+    #[test]
+    fn test_transitions() {
+        let mut dualsense = controller("DualSense", "/dev/hidraw0", 15, Status::Discharging);
+        dualsense.serial_number = Some("a0:ab:51:12:34:56".to_string());
+        let previous = vec![
+            dualsense.clone(),
+            controller("Xbox", "/dev/hidraw1", 100, Status::Charging),
+        ];
+        assert!(transitions(&previous, &previous).is_empty());
+
+        // Plugging in the cable gives the controller a new hidraw device
+        dualsense.device_path = Some("/dev/hidraw3".to_string());
+        dualsense.capacity = 16;
+        dualsense.status = Status::Charging;
+        dualsense.bluetooth = false;
+        let current = vec![
+            dualsense,
+            controller("Joy-Con", "/dev/hidraw2", 50, Status::Unknown),
+        ];
+        assert_eq!(
+            transitions(&previous, &current),
+            vec![
+                "DualSense: discharging → charging (16%)",
+                "DualSense: Bluetooth → USB",
+                "Joy-Con connected over Bluetooth (50%, unknown)",
+                "Xbox disconnected (100%)",
+            ]
+        );
+    }
+}
@@ -1,11 +1,11 @@
-use std::ffi::OsStr;
+use std::{ffi::OsStr, path::Path};
 
 use hidapi::DeviceInfo;
 use log::error;
 use serde::{Deserialize, Serialize};
 use udev::Device;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Status {
     Charging,
@@ -13,7 +13,7 @@ pub enum Status {
     Unknown,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Controller {
     pub name: String,
@@ -103,6 +103,41 @@ impl Controller {
         }
     }
 
+    /// Identifies the controller across reconnects and restarts, unlike `id()` which follows the
+    /// device path. Only made of lowercase letters, digits and dashes so it fits in URLs and file
+    /// names.
+    ///
+    /// Controllers without a serial number fall back to the name of their device node, so that
+    /// two identical controllers don't share an id. Such an id only lasts until the controller
+    /// gets another device node, so its history and settings may not survive a reconnect.
+    pub fn stable_id(&self) -> String {
+        let device_name = self
+            .device_path
+            .as_deref()
+            .and_then(|device_path| Path::new(device_path).file_name())
+            .map(|device_name| device_name.to_string_lossy());
+        let id = match (&self.serial_number, device_name) {
+            (Some(serial_number), _) => format!(
+                "{:04x}-{:04x}-{}",
+                self.vendor_id, self.product_id, serial_number
+            ),
+            (None, Some(device_name)) => format!(
+                "{:04x}-{:04x}-{}",
+                self.vendor_id, self.product_id, device_name
+            ),
+            (None, None) => format!("{:04x}-{:04x}", self.vendor_id, self.product_id),
+        };
+        id.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+
     pub fn is_discharging(&self) -> bool {
         self.status == Status::Discharging
     }
@@ -186,6 +221,48 @@ mod tests {
         assert_eq!(controller.id(), "746:1118");
     }
 
+    #[test]
+    fn test_stable_id() {
+        let mut controller = Controller {
+            name: "Test Controller".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity: 0,
+            status: Status::Discharging,
+            bluetooth: true,
+            device_path: Some("/dev/hidraw3".to_string()),
+            serial_number: Some("A0:AB:51:12:34:56".to_string()),
+        };
+
+        assert_eq!(controller.stable_id(), "054c-0ce6-a0-ab-51-12-34-56");
+        controller.device_path = None;
+        assert_eq!(controller.stable_id(), "054c-0ce6-a0-ab-51-12-34-56");
+        controller.serial_number = None;
+        assert_eq!(controller.stable_id(), "054c-0ce6");
+    }
+
+    #[test]
+    fn test_stable_id_without_serial_number() {
+        let controller = |device_path: &str| Controller {
+            name: "Test Controller".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity: 0,
+            status: Status::Discharging,
+            bluetooth: false,
+            device_path: Some(device_path.to_string()),
+            serial_number: None,
+        };
+
+        // Two identical controllers must not share their history and settings
+        assert_eq!(controller("/dev/hidraw3").stable_id(), "054c-0ce6-hidraw3");
+        assert_eq!(controller("/dev/hidraw4").stable_id(), "054c-0ce6-hidraw4");
+        assert_eq!(
+            controller("/devices/virtual/power_supply/ps-controller-battery-0").stable_id(),
+            "054c-0ce6-ps-controller-battery-0"
+        );
+    }
+
     #[test]
     fn test_hex_os_str_to_u16() {
         let os_str = OsStr::new("045e");
@@ -0,0 +1,483 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use log::{error, info};
+use serde::Serialize;
+use tokio::sync::{broadcast::error::RecvError, watch};
+use zbus::{
+    connection,
+    fdo::ObjectManager,
+    interface,
+    object_server::SignalEmitter,
+    zvariant::{ObjectPath, OwnedObjectPath, Type},
+    Connection,
+};
+
+use crate::{
+    controller::{Controller, Status},
+    protocol::ServerMessage,
+    AppState,
+};
+
+pub const NAME: &str = "io.github.alphamercury.ControllerTools";
+const PATH: &str = "/io/github/alphamercury/ControllerTools";
+
+/// The bus the D-Bus service is on
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Bus {
+    Session,
+    System,
+}
+
+/// Serves the controllers found by the monitor on D-Bus, for local tools that would rather not
+/// speak HTTP: a `Controllers` object with the list and the connect, disconnect and low battery
+/// signals, and an object with the properties of each connected controller below it.
+pub async fn serve(state: Arc<AppState>, bus: Bus) -> Result<()> {
+    let builder = match bus {
+        Bus::Session => connection::Builder::session()?,
+        Bus::System => connection::Builder::system()?,
+    };
+    let connection = builder
+        .name(NAME)?
+        .serve_at(PATH, ObjectManager)?
+        .serve_at(
+            PATH,
+            Controllers {
+                controllers: state.controllers.subscribe(),
+            },
+        )?
+        .build()
+        .await?;
+    info!("Serving {} on the {:?} bus", NAME, bus);
+    run(&connection, &state).await
+}
+
+async fn run(connection: &Connection, state: &AppState) -> Result<()> {
+    let mut service = Service::new(connection)?;
+    let mut controllers = state.controllers.subscribe();
+    let mut alerts = state.alert_service.subscribe();
+    let current = controllers.borrow_and_update().clone();
+    service.update(&current).await?;
+
+    loop {
+        tokio::select! {
+            changed = controllers.changed() => {
+                changed?;
+                let current = controllers.borrow_and_update().clone();
+                service.update(&current).await?;
+            }
+            alerts = alerts.recv() => match alerts {
+                Ok(alerts) => {
+                    for message in alerts.iter().flat_map(ServerMessage::ungroup) {
+                        service.alert(message).await?;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    error!("D-Bus service missed {} batches of alerts", missed);
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// A controller as returned by `List`
+#[derive(Debug, Serialize, Type, PartialEq)]
+pub struct ControllerInfo {
+    path: OwnedObjectPath,
+    id: String,
+    name: String,
+    vendor_id: u16,
+    product_id: u16,
+    capacity: u8,
+    status: String,
+    bluetooth: bool,
+}
+
+impl From<&Controller> for ControllerInfo {
+    fn from(controller: &Controller) -> Self {
+        let id = controller.stable_id();
+        Self {
+            path: controller_path(&id),
+            id,
+            name: controller.name.clone(),
+            vendor_id: controller.vendor_id,
+            product_id: controller.product_id,
+            capacity: controller.capacity,
+            status: status(controller.status).to_string(),
+            bluetooth: controller.bluetooth,
+        }
+    }
+}
+
+struct Controllers {
+    controllers: watch::Receiver<Vec<Controller>>,
+}
+
+#[interface(name = "io.github.alphamercury.ControllerTools.Controllers")]
+impl Controllers {
+    /// The connected controllers, as of the monitor's latest check
+    fn list(&self) -> Vec<ControllerInfo> {
+        self.controllers.borrow().iter().map(Into::into).collect()
+    }
+
+    #[zbus(signal)]
+    async fn connected(
+        emitter: &SignalEmitter<'_>,
+        path: &ObjectPath<'_>,
+        id: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn disconnected(
+        emitter: &SignalEmitter<'_>,
+        path: &ObjectPath<'_>,
+        id: &str,
+    ) -> zbus::Result<()>;
+
+    /// Sent with the low battery alerts, so it follows the notification settings
+    #[zbus(signal)]
+    async fn low_battery(
+        emitter: &SignalEmitter<'_>,
+        path: &ObjectPath<'_>,
+        id: &str,
+        capacity: u8,
+        critical: bool,
+        message: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// The object of a connected controller
+struct ControllerObject {
+    controller: Controller,
+}
+
+#[interface(name = "io.github.alphamercury.ControllerTools.Controller")]
+impl ControllerObject {
+    /// Same as `Controller::stable_id`, e.g. the key of the controller's settings
+    #[zbus(property)]
+    fn id(&self) -> String {
+        self.controller.stable_id()
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> &str {
+        &self.controller.name
+    }
+
+    #[zbus(property)]
+    fn vendor_id(&self) -> u16 {
+        self.controller.vendor_id
+    }
+
+    #[zbus(property)]
+    fn product_id(&self) -> u16 {
+        self.controller.product_id
+    }
+
+    #[zbus(property)]
+    fn capacity(&self) -> u8 {
+        self.controller.capacity
+    }
+
+    /// One of `charging`, `discharging` or `unknown`
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        status(self.controller.status)
+    }
+
+    #[zbus(property)]
+    fn bluetooth(&self) -> bool {
+        self.controller.bluetooth
+    }
+}
+
+/// Keeps the controller objects in line with the monitor's checks
+struct Service {
+    connection: Connection,
+    emitter: SignalEmitter<'static>,
+    // Exported controllers, by stable id
+    exported: BTreeMap<String, Controller>,
+}
+
+impl Service {
+    fn new(connection: &Connection) -> Result<Self> {
+        Ok(Self {
+            connection: connection.clone(),
+            emitter: SignalEmitter::new(connection, PATH)?,
+            exported: BTreeMap::new(),
+        })
+    }
+
+    async fn update(&mut self, controllers: &[Controller]) -> Result<()> {
+        let connection = self.connection.clone();
+        let object_server = connection.object_server();
+        let current: BTreeMap<String, &Controller> = controllers
+            .iter()
+            .map(|controller| (controller.stable_id(), controller))
+            .collect();
+
+        let gone: Vec<String> = self
+            .exported
+            .keys()
+            .filter(|id| !current.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in gone {
+            let path = controller_path(&id);
+            object_server.remove::<ControllerObject, _>(&path).await?;
+            self.exported.remove(&id);
+            Controllers::disconnected(&self.emitter, &path, &id).await?;
+        }
+
+        for (id, controller) in current {
+            let path = controller_path(&id);
+            let Some(exported) = self.exported.get(&id).cloned() else {
+                let object = ControllerObject {
+                    controller: controller.clone(),
+                };
+                object_server.at(&path, object).await?;
+                self.exported.insert(id.clone(), controller.clone());
+                Controllers::connected(&self.emitter, &path, &id).await?;
+                continue;
+            };
+            if exported == *controller {
+                continue;
+            }
+
+            let object = object_server
+                .interface::<_, ControllerObject>(&path)
+                .await?;
+            object.get_mut().await.controller = controller.clone();
+            let object_ref = object.get().await;
+            let emitter = object.signal_emitter();
+            if exported.name != controller.name {
+                object_ref.name_changed(emitter).await?;
+            }
+            if exported.capacity != controller.capacity {
+                object_ref.capacity_changed(emitter).await?;
+            }
+            if exported.status != controller.status {
+                object_ref.status_changed(emitter).await?;
+            }
+            if exported.bluetooth != controller.bluetooth {
+                object_ref.bluetooth_changed(emitter).await?;
+            }
+            self.exported.insert(id, controller.clone());
+        }
+        Ok(())
+    }
+
+    async fn alert(&self, message: &ServerMessage) -> Result<()> {
+        if let ServerMessage::LowBattery(alert) = message {
+            Controllers::low_battery(
+                &self.emitter,
+                &controller_path(&alert.stable_id),
+                &alert.stable_id,
+                alert.capacity,
+                alert.critical,
+                &alert.message,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Object path of a controller, from its stable id which is already made of path-safe characters
+/// but for the dashes
+fn controller_path(stable_id: &str) -> OwnedObjectPath {
+    let path = format!("{}/controllers/{}", PATH, stable_id.replace('-', "_"));
+    OwnedObjectPath::try_from(path).expect("Invalid controller path")
+}
+
+fn status(status: Status) -> &'static str {
+    match status {
+        Status::Charging => "charging",
+        Status::Discharging => "discharging",
+        Status::Unknown => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::sync::watch;
+    use zbus::{connection, fdo::ObjectManager, proxy, zvariant::OwnedObjectPath, Guid};
+
+    use super::{ControllerInfo, Controllers, Service, PATH};
+    use crate::{
+        controller::{Controller, Status},
+        protocol::{BatteryAlert, ServerMessage},
+    };
+
+    #[proxy(
+        interface = "io.github.alphamercury.ControllerTools.Controllers",
+        default_service = "io.github.alphamercury.ControllerTools",
+        default_path = "/io/github/alphamercury/ControllerTools"
+    )]
+    trait Controllers {
+        fn list(
+            &self,
+        ) -> zbus::Result<Vec<(OwnedObjectPath, String, String, u16, u16, u8, String, bool)>>;
+
+        #[zbus(signal)]
+        fn connected(&self, path: OwnedObjectPath, id: String) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        fn disconnected(&self, path: OwnedObjectPath, id: String) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        fn low_battery(
+            &self,
+            path: OwnedObjectPath,
+            id: String,
+            capacity: u8,
+            critical: bool,
+            message: String,
+        ) -> zbus::Result<()>;
+    }
+
+    #[proxy(
+        interface = "io.github.alphamercury.ControllerTools.Controller",
+        default_service = "io.github.alphamercury.ControllerTools"
+    )]
+    trait Controller {
+        #[zbus(property)]
+        fn capacity(&self) -> zbus::Result<u8>;
+
+        #[zbus(property)]
+        fn status(&self) -> zbus::Result<String>;
+    }
+
+    fn controller(capacity: u8) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity,
+            status: Status::Discharging,
+            bluetooth: true,
+            serial_number: Some("a0:ab:51:12:34:56".to_string()),
+            device_path: Some("/dev/hidraw1".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service() -> anyhow::Result<()> {
+        let (sender, receiver) = watch::channel(Vec::new());
+        // A peer to peer connection stands in for the bus
+        let (server, client) = tokio::net::UnixStream::pair()?;
+        let server = connection::Builder::unix_stream(server)
+            .server(Guid::generate())?
+            .p2p()
+            .serve_at(PATH, ObjectManager)?
+            .serve_at(
+                PATH,
+                Controllers {
+                    controllers: receiver,
+                },
+            )?
+            .build();
+        let client = connection::Builder::unix_stream(client).p2p().build();
+        let (server, client) = tokio::try_join!(server, client)?;
+
+        let controllers = ControllersProxy::new(&client).await?;
+        let mut connected = controllers.receive_connected().await?;
+        let mut disconnected = controllers.receive_disconnected().await?;
+        let mut low_battery = controllers.receive_low_battery().await?;
+        assert!(controllers.list().await?.is_empty());
+
+        let mut service = Service::new(&server)?;
+        sender.send_replace(vec![controller(40)]);
+        service.update(&sender.borrow()).await?;
+        let signal = tokio::time::timeout(Duration::from_secs(5), connected.next())
+            .await?
+            .unwrap();
+        let args = signal.args()?;
+        let path =
+            "/io/github/alphamercury/ControllerTools/controllers/054c_0ce6_a0_ab_51_12_34_56";
+        assert_eq!(args.path.as_str(), path);
+        assert_eq!(args.id, "054c-0ce6-a0-ab-51-12-34-56");
+
+        let list = controllers.list().await?;
+        assert_eq!(list.len(), 1);
+        let expected = ControllerInfo::from(&controller(40));
+        assert_eq!(list[0].0, expected.path);
+        assert_eq!(list[0].5, 40);
+        assert_eq!(list[0].6, "discharging");
+
+        let mut charging = controller(45);
+        charging.status = Status::Charging;
+        sender.send_replace(vec![charging]);
+        service.update(&sender.borrow()).await?;
+        let object = ControllerProxy::builder(&client)
+            .path(path)?
+            .build()
+            .await?;
+        assert_eq!(object.capacity().await?, 45);
+        assert_eq!(object.status().await?, "charging");
+
+        let alert = BatteryAlert::new(&controller(5), "DualSense is critically low".to_string());
+        service.alert(&ServerMessage::LowBattery(alert)).await?;
+        let signal = tokio::time::timeout(Duration::from_secs(5), low_battery.next())
+            .await?
+            .unwrap();
+        assert_eq!(signal.args()?.capacity, 5);
+
+        sender.send_replace(Vec::new());
+        service.update(&sender.borrow()).await?;
+        let signal = tokio::time::timeout(Duration::from_secs(5), disconnected.next())
+            .await?
+            .unwrap();
+        assert_eq!(signal.args()?.path.as_str(), path);
+        assert!(controllers.list().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_service_without_serial_numbers() -> anyhow::Result<()> {
+        let (sender, receiver) = watch::channel(Vec::new());
+        let (server, client) = tokio::net::UnixStream::pair()?;
+        let server = connection::Builder::unix_stream(server)
+            .server(Guid::generate())?
+            .p2p()
+            .serve_at(
+                PATH,
+                Controllers {
+                    controllers: receiver,
+                },
+            )?
+            .build();
+        let client = connection::Builder::unix_stream(client).p2p().build();
+        let (server, client) = tokio::try_join!(server, client)?;
+
+        // Two identical controllers that don't report a serial number
+        let controller = |capacity, device_path: &str| Controller {
+            serial_number: None,
+            device_path: Some(device_path.to_string()),
+            ..controller(capacity)
+        };
+        let mut service = Service::new(&server)?;
+        sender.send_replace(vec![
+            controller(40, "/dev/hidraw1"),
+            controller(80, "/dev/hidraw2"),
+        ]);
+        service.update(&sender.borrow()).await?;
+
+        let controllers = ControllersProxy::new(&client).await?;
+        let list = controllers.list().await?;
+        assert_eq!(list.len(), 2);
+        for (path, capacity) in [("054c_0ce6_hidraw1", 40), ("054c_0ce6_hidraw2", 80)] {
+            let object = ControllerProxy::builder(&client)
+                .path(format!("{}/controllers/{}", PATH, path))?
+                .build()
+                .await?;
+            assert_eq!(object.capacity().await?, capacity);
+        }
+        Ok(())
+    }
+}
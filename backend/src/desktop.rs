@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use log::{debug, error, info};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use zbus::{proxy, zvariant::Value, Connection};
+
+use crate::{alerts::unix_now, protocol::ServerMessage, AppState};
+
+const APP_NAME: &str = "Controller Tools";
+// Generic icon of the freedesktop icon naming spec, shown by servers that don't support the
+// `image-path` hint the vendor's icon is sent in
+const ICON: &str = "input-gaming";
+const SNOOZE_ACTION: &str = "snooze";
+// How long the Snooze button holds back the alerts
+const SNOOZE_MINUTES: u64 = 60;
+
+// Urgency levels of the notification spec
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+
+#[proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: String) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// What the Snooze button of a notification holds back
+#[derive(Debug, Clone, PartialEq)]
+pub enum Snooze {
+    /// The alerts of the controller with this `Controller::stable_id`
+    Controller(String),
+    All,
+}
+
+/// A desktop notification for an alert
+#[derive(Debug, PartialEq)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    pub icon: &'static str,
+    pub critical: bool,
+    pub snooze: Snooze,
+}
+
+impl Notification {
+    /// The notification for a message, if it's an alert
+    pub fn new(message: &ServerMessage) -> Option<Self> {
+        let (summary, body, icon, critical, snooze) = match message {
+            ServerMessage::AlertGroup(group) => {
+                let summary = match group.critical {
+                    true => "Controllers critically low",
+                    false => "Controllers",
+                };
+                (summary, &group.message, ICON, group.critical, Snooze::All)
+            }
+            ServerMessage::Digest(digest) => (
+                "While you were away",
+                &digest.message,
+                ICON,
+                false,
+                Snooze::All,
+            ),
+            message => {
+                let alert = message.alert()?;
+                let summary = match message {
+                    _ if alert.critical => "Battery critically low",
+                    ServerMessage::LowBattery(_) => "Battery low",
+                    ServerMessage::Charged(_) => "Fully charged",
+                    ServerMessage::ChargingStarted(_) => "Charging",
+                    ServerMessage::ConnectedAlert(_) => "Connected",
+                    _ => "Disconnected",
+                };
+                let snooze = Snooze::Controller(alert.stable_id.clone());
+                (
+                    summary,
+                    &alert.message,
+                    icon(alert.vendor_id),
+                    alert.critical,
+                    snooze,
+                )
+            }
+        };
+        Some(Self {
+            summary: summary.to_string(),
+            body: body.clone(),
+            icon,
+            critical,
+            snooze,
+        })
+    }
+}
+
+/// Icon of a controller's vendor, `ICON` for vendors without one. Icon themes without the
+/// vendor's own icon fall back to `input-gaming` by dropping the suffix.
+fn icon(vendor_id: u16) -> &'static str {
+    match vendor_id {
+        0x054c => "input-gaming-sony",
+        0x057e => "input-gaming-nintendo",
+        0x045e => "input-gaming-microsoft",
+        0x28de => "input-gaming-valve",
+        _ => ICON,
+    }
+}
+
+/// Shows notifications through `org.freedesktop.Notifications` and reports the Snooze buttons
+/// the user clicks
+pub struct Notifier {
+    proxy: NotificationsProxy<'static>,
+    // What the Snooze button of each notification still on screen does
+    snoozes: Arc<Mutex<HashMap<u32, Snooze>>>,
+}
+
+impl Notifier {
+    /// Clicks on a Snooze button are sent to `snoozed`
+    pub async fn new(
+        connection: &Connection,
+        snoozed: mpsc::UnboundedSender<Snooze>,
+    ) -> Result<Self> {
+        let proxy = NotificationsProxy::new(connection).await?;
+        let mut actions = proxy.receive_action_invoked().await?;
+        let mut closed = proxy.receive_notification_closed().await?;
+        let snoozes: Arc<Mutex<HashMap<u32, Snooze>>> = Arc::default();
+
+        let pending = snoozes.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(signal) = actions.next() => {
+                        let Ok(args) = signal.args() else { continue };
+                        if args.action_key != SNOOZE_ACTION {
+                            continue;
+                        }
+                        let snooze = pending.lock().unwrap().remove(&args.id);
+                        if let Some(snooze) = snooze {
+                            if snoozed.send(snooze).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Some(signal) = closed.next() => {
+                        if let Ok(args) = signal.args() {
+                            pending.lock().unwrap().remove(&args.id);
+                        }
+                    }
+                    else => break,
+                }
+            }
+        });
+
+        Ok(Self { proxy, snoozes })
+    }
+
+    pub async fn show(&self, notification: Notification) -> Result<u32> {
+        let urgency = match notification.critical {
+            true => URGENCY_CRITICAL,
+            false => URGENCY_NORMAL,
+        };
+        let mut hints = HashMap::from([
+            ("urgency", Value::from(urgency)),
+            ("category", Value::from("device")),
+        ]);
+        if notification.icon != ICON {
+            // Takes the place of the app icon on servers that support it
+            hints.insert("image-path", Value::from(notification.icon));
+        }
+        let id = self
+            .proxy
+            .notify(
+                APP_NAME,
+                0,
+                ICON,
+                &notification.summary,
+                &notification.body,
+                &[SNOOZE_ACTION, "Snooze"],
+                hints,
+                // The server's default
+                -1,
+            )
+            .await?;
+        self.snoozes.lock().unwrap().insert(id, notification.snooze);
+        Ok(id)
+    }
+}
+
+/// Shows the alerts published by the monitor as desktop notifications while they are enabled in
+/// the settings. The session bus is only connected to once they are.
+pub async fn run(state: Arc<AppState>) -> Result<()> {
+    let mut alerts = state.alert_service.subscribe();
+    let (snoozed, mut snoozes) = mpsc::unbounded_channel();
+    let mut notifier = None;
+
+    loop {
+        tokio::select! {
+            alerts = alerts.recv() => {
+                let alerts = match alerts {
+                    Ok(alerts) => alerts,
+                    Err(RecvError::Lagged(missed)) => {
+                        error!("Desktop notifications missed {} batches of alerts", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                if !state.settings_service.get_settings().await.desktop_notifications {
+                    continue;
+                }
+                if notifier.is_none() {
+                    // Tried again with the next alerts, e.g. once the desktop session is up
+                    match connect(snoozed.clone()).await {
+                        Ok(connected) => notifier = Some(connected),
+                        Err(err) => error!("Failed to set up desktop notifications: {:#}", err),
+                    }
+                }
+                let Some(notifier) = &notifier else { continue };
+                for notification in alerts.iter().filter_map(Notification::new) {
+                    debug!("Showing desktop notification: {}", notification.body);
+                    if let Err(err) = notifier.show(notification).await {
+                        error!("Failed to show desktop notification: {:#}", err);
+                    }
+                }
+            }
+            Some(snooze) = snoozes.recv() => {
+                info!("Snoozed from a desktop notification: {:?}", snooze);
+                let until = unix_now() + SNOOZE_MINUTES * 60;
+                state
+                    .alert_service
+                    .update(|alerts| match snooze {
+                        Snooze::Controller(id) => {
+                            alerts.snoozed_until.insert(id, until);
+                        }
+                        Snooze::All => alerts.snoozed_all_until = Some(until),
+                    })
+                    .await;
+            }
+        }
+    }
+}
+
+async fn connect(snoozed: mpsc::UnboundedSender<Snooze>) -> Result<Notifier> {
+    let connection = Connection::session()
+        .await
+        .context("Failed to connect to the session bus")?;
+    Notifier::new(&connection, snoozed).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+    use zbus::{
+        connection, interface, object_server::SignalEmitter, zvariant::OwnedValue, Connection, Guid,
+    };
+
+    use super::{icon, Notification, Notifier, Snooze};
+    use crate::controller::{Controller, Status};
+    use crate::monitor::group_alerts;
+    use crate::protocol::{BatteryAlert, ErrorCode, ServerMessage};
+    use crate::settings::Settings;
+
+    const PATH: &str = "/org/freedesktop/Notifications";
+
+    #[derive(Debug)]
+    struct Shown {
+        icon: String,
+        image: Option<String>,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        urgency: u8,
+    }
+
+    /// Stands in for the desktop's notification server
+    #[derive(Default)]
+    struct StubServer {
+        shown: Arc<Mutex<Vec<Shown>>>,
+    }
+
+    #[interface(name = "org.freedesktop.Notifications")]
+    impl StubServer {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: String,
+            _replaces_id: u32,
+            app_icon: String,
+            summary: String,
+            body: String,
+            actions: Vec<String>,
+            hints: HashMap<String, OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let mut shown = self.shown.lock().unwrap();
+            shown.push(Shown {
+                icon: app_icon,
+                image: hints
+                    .get("image-path")
+                    .map(|image| String::try_from(image.clone()).unwrap()),
+                summary,
+                body,
+                actions,
+                urgency: u8::try_from(&hints["urgency"]).unwrap(),
+            });
+            shown.len() as u32
+        }
+
+        #[zbus(signal)]
+        async fn action_invoked(
+            emitter: &SignalEmitter<'_>,
+            id: u32,
+            action_key: &str,
+        ) -> zbus::Result<()>;
+    }
+
+    fn controller(vendor_id: u16, capacity: u8) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id,
+            capacity,
+            status: Status::Discharging,
+            bluetooth: true,
+            serial_number: Some("hidraw1".to_string()),
+            device_path: Some("/dev/hidraw1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_notification() {
+        let message = "DualSense is critically low on battery (5%)".to_string();
+        let mut alert = BatteryAlert::new(&controller(0x054c, 5), message);
+        alert.critical = true;
+        let notification = Notification::new(&ServerMessage::LowBattery(alert)).unwrap();
+        assert_eq!(notification.summary, "Battery critically low");
+        assert_eq!(notification.icon, "input-gaming-sony");
+        assert!(notification.critical);
+        assert_eq!(
+            notification.snooze,
+            Snooze::Controller("054c-0ce6-hidraw1".to_string())
+        );
+
+        let alert = BatteryAlert::new(&controller(0x1234, 100), "Charged".to_string());
+        let notification = Notification::new(&ServerMessage::Charged(alert)).unwrap();
+        assert_eq!(notification.summary, "Fully charged");
+        assert_eq!(notification.icon, "input-gaming");
+        assert!(!notification.critical);
+
+        let error = ServerMessage::error(ErrorCode::ProbeFailed, "No hidraw devices");
+        assert!(Notification::new(&error).is_none());
+    }
+
+    #[test]
+    fn test_icon() {
+        assert_eq!(icon(0x054c), "input-gaming-sony");
+        assert_eq!(icon(0x057e), "input-gaming-nintendo");
+        assert_eq!(icon(0x045e), "input-gaming-microsoft");
+        assert_eq!(icon(0x28de), "input-gaming-valve");
+        assert_eq!(icon(0x1234), "input-gaming");
+    }
+
+    /// Connections to a stub server and the notifications it was asked to show
+    async fn stub_server() -> anyhow::Result<(Connection, Connection, Arc<Mutex<Vec<Shown>>>)> {
+        // A peer to peer connection, no bus needed
+        let (server, client) = tokio::net::UnixStream::pair()?;
+        let stub = StubServer::default();
+        let shown = stub.shown.clone();
+        let server = connection::Builder::unix_stream(server)
+            .server(Guid::generate())?
+            .p2p()
+            .serve_at(PATH, stub)?
+            .build();
+        let client = connection::Builder::unix_stream(client).p2p().build();
+        let (server, client) = tokio::try_join!(server, client)?;
+        Ok((server, client, shown))
+    }
+
+    #[tokio::test]
+    async fn test_notifier() -> anyhow::Result<()> {
+        let (server, client, shown) = stub_server().await?;
+
+        let (snoozed, mut snoozes) = mpsc::unbounded_channel();
+        let notifier = Notifier::new(&client, snoozed).await?;
+        let mut alert = BatteryAlert::new(&controller(0x054c, 5), "Low".to_string());
+        alert.critical = true;
+        let notification = Notification::new(&ServerMessage::LowBattery(alert)).unwrap();
+        let id = notifier.show(notification).await?;
+
+        {
+            let shown = shown.lock().unwrap();
+            assert_eq!(shown.len(), 1);
+            assert_eq!(shown[0].icon, "input-gaming");
+            assert_eq!(shown[0].image.as_deref(), Some("input-gaming-sony"));
+            assert_eq!(shown[0].summary, "Battery critically low");
+            assert_eq!(shown[0].actions, ["snooze", "Snooze"]);
+            assert_eq!(shown[0].urgency, 2);
+        }
+
+        // The user clicks Snooze
+        let emitter = SignalEmitter::new(&server, PATH)?;
+        StubServer::action_invoked(&emitter, id, "snooze").await?;
+        let snooze = tokio::time::timeout(Duration::from_secs(5), snoozes.recv()).await?;
+        assert_eq!(
+            snooze,
+            Some(Snooze::Controller("054c-0ce6-hidraw1".to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notifier_alert_group() -> anyhow::Result<()> {
+        let (server, client, shown) = stub_server().await?;
+
+        let (snoozed, mut snoozes) = mpsc::unbounded_channel();
+        let notifier = Notifier::new(&client, snoozed).await?;
+        let mut critical = BatteryAlert::new(
+            &controller(0x054c, 5),
+            "DualSense is critically low on battery (5%)".to_string(),
+        );
+        critical.critical = true;
+        let mut low = BatteryAlert::new(
+            &controller(0x057e, 15),
+            "Pro Controller is low on battery (15%)".to_string(),
+        );
+        low.name = "Pro Controller".to_string();
+        let messages = group_alerts(
+            vec![
+                ServerMessage::LowBattery(critical),
+                ServerMessage::LowBattery(low),
+            ],
+            &Settings::default(),
+        );
+        assert_eq!(messages.len(), 1);
+        let notification = Notification::new(&messages[0]).unwrap();
+        let id = notifier.show(notification).await?;
+
+        {
+            let shown = shown.lock().unwrap();
+            assert_eq!(shown.len(), 1);
+            assert_eq!(shown[0].icon, "input-gaming");
+            assert_eq!(shown[0].image, None);
+            assert_eq!(shown[0].summary, "Controllers critically low");
+            assert_eq!(
+                shown[0].body,
+                "2 controllers low: DualSense 5%, Pro Controller 15%"
+            );
+            assert_eq!(shown[0].urgency, 2);
+        }
+
+        // Snoozing a group holds back every alert
+        let emitter = SignalEmitter::new(&server, PATH)?;
+        StubServer::action_invoked(&emitter, id, "snooze").await?;
+        let snooze = tokio::time::timeout(Duration::from_secs(5), snoozes.recv()).await?;
+        assert_eq!(snooze, Some(Snooze::All));
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use serde::Serialize;
+
+use crate::{controller::Status, history::Sample};
+
+// Readings further apart than this belong to different sessions, e.g. the controller was off
+pub const MAX_GAP_SECS: u64 = 15 * 60;
+// Estimates less confident than this aren't worth showing in a notification
+pub const MIN_NOTIFICATION_CONFIDENCE: f64 = 0.3;
+
+/// How long until the battery is empty or full, from the recent history of a controller
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Estimate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minutes_remaining: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minutes_to_full: Option<u32>,
+    /// From 0 to 1, grows with the number of readings the estimate is based on and drops when
+    /// the battery drains or charges irregularly
+    pub confidence: f64,
+}
+
+/// Estimates the time left from the samples of the current discharge or charge. The capacity
+/// only moves in steps of `step`, so the rate is measured between the moments it changed,
+/// which are the only moments we know the actual capacity, at the edge of a bucket.
+pub fn estimate(samples: &[Sample], step: u8, now: u64) -> Option<Estimate> {
+    let last = samples.last()?;
+    let charging = match last.status {
+        Status::Discharging => false,
+        Status::Charging if last.capacity < 100 => true,
+        _ => return None,
+    };
+
+    // Walk back to the start of the current run: same status, capacity only moving one way and
+    // no long gap. The wall clock going back, e.g. when it gets corrected, counts as a gap.
+    let mut start = samples.len() - 1;
+    while start > 0 {
+        let (previous, sample) = (&samples[start - 1], &samples[start]);
+        let monotonic = if charging {
+            previous.capacity <= sample.capacity
+        } else {
+            previous.capacity >= sample.capacity
+        };
+        let elapsed = sample.time.checked_sub(previous.time);
+        if previous.status != last.status
+            || !monotonic
+            || elapsed.is_none_or(|elapsed| elapsed > MAX_GAP_SECS)
+        {
+            break;
+        }
+        start -= 1;
+    }
+    if now.saturating_sub(last.time) > MAX_GAP_SECS {
+        return None;
+    }
+
+    // The samples where the capacity moved to a new bucket
+    let run = &samples[start..];
+    let changes: Vec<&Sample> = run
+        .windows(2)
+        .filter(|pair| pair[0].capacity != pair[1].capacity)
+        .map(|pair| &pair[1])
+        .collect();
+    let (first, latest) = (changes.first()?, changes.last()?);
+    if latest.time <= first.time {
+        return None;
+    }
+
+    // Percent per second
+    let rate = latest.capacity.abs_diff(first.capacity) as f64
+        / latest.time.saturating_sub(first.time) as f64;
+    let since_change = now.saturating_sub(latest.time) as f64;
+    let half_step = (step / 2) as f64;
+    let seconds = if charging {
+        let bucket_bottom = (latest.capacity as f64 - half_step).max(0.0);
+        (100.0 - bucket_bottom) / rate - since_change
+    } else {
+        let bucket_top = (latest.capacity as f64 + half_step).min(100.0);
+        bucket_top / rate - since_change
+    };
+    let minutes = (seconds.max(0.0) / 60.0).round() as u32;
+
+    // Steady rates between the changes give confidence, more of them even more
+    let rates: Vec<f64> = changes
+        .windows(2)
+        .map(|pair| {
+            pair[1].capacity.abs_diff(pair[0].capacity) as f64
+                / pair[1].time.saturating_sub(pair[0].time).max(1) as f64
+        })
+        .collect();
+    let mean = rates.iter().sum::<f64>() / rates.len() as f64;
+    let variance = rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / rates.len() as f64;
+    let variation = variance.sqrt() / mean;
+    let intervals = rates.len() as f64;
+    let mut confidence = intervals / (intervals + 2.0) / (1.0 + variation);
+    // The current bucket lasting much longer than the previous ones means the rate changed
+    let average_interval = latest.time.saturating_sub(first.time) as f64 / intervals;
+    if since_change > 2.0 * average_interval {
+        confidence /= 2.0;
+    }
+    let confidence = (confidence * 100.0).round() / 100.0;
+
+    Some(Estimate {
+        minutes_remaining: (!charging).then_some(minutes),
+        minutes_to_full: charging.then_some(minutes),
+        confidence,
+    })
+}
+
+/// "about 25 minutes left", for notifications
+pub fn describe_remaining(minutes: u32) -> String {
+    match minutes {
+        0..=4 => "less than 5 minutes left".to_string(),
+        5..=59 => format!("about {} minutes left", (minutes + 2) / 5 * 5),
+        _ => {
+            let (hours, minutes) = (minutes / 60, (minutes % 60 + 7) / 15 * 15);
+            match (hours, minutes) {
+                (hours, 60) => format!("about {} hours left", hours + 1),
+                (1, 0) => "about 1 hour left".to_string(),
+                (hours, 0) => format!("about {} hours left", hours),
+                (hours, minutes) => format!("about {}h{:02} left", hours, minutes),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{controller::Status, history::Sample};
+
+    use super::{describe_remaining, estimate};
+
+    fn samples(readings: &[(u64, u8, Status)]) -> Vec<Sample> {
+        readings
+            .iter()
+            .map(|&(time, capacity, status)| Sample {
+                time,
+                capacity,
+                status,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_estimate_discharge() {
+        // A DualSense losing 10% every 10 minutes, polled every minute
+        let mut readings = Vec::new();
+        for minute in 0..=30 {
+            readings.push((
+                minute * 60,
+                55 - (minute / 10) as u8 * 10,
+                Status::Discharging,
+            ));
+        }
+        let samples = samples(&readings);
+        let estimate = estimate(&samples, 10, 30 * 60).unwrap();
+        // Dropped to 25% (20-30%) right now, 30% left at 1% per minute
+        assert_eq!(estimate.minutes_remaining, Some(30));
+        assert_eq!(estimate.minutes_to_full, None);
+        assert_eq!(estimate.confidence, 0.5);
+
+        // 4 minutes into the bucket
+        let estimate = super::estimate(&samples, 10, 34 * 60).unwrap();
+        assert_eq!(estimate.minutes_remaining, Some(26));
+
+        // Not enough changes yet
+        assert!(super::estimate(&samples[..15], 10, 15 * 60).is_none());
+        // Stale readings
+        assert!(super::estimate(&samples, 10, 60 * 60).is_none());
+    }
+
+    #[test]
+    fn test_estimate_clock_going_back() {
+        // The same discharge as above, but the clock is set back an hour after 15 minutes
+        let mut readings = Vec::new();
+        for minute in 0..=30 {
+            let time = match minute {
+                0..=15 => 10_000 + minute * 60,
+                _ => 10_000 - 3600 + minute * 60,
+            };
+            readings.push((time, 55 - (minute / 10) as u8 * 10, Status::Discharging));
+        }
+        let samples = samples(&readings);
+        let now = samples.last().unwrap().time;
+        // Only the readings since the clock changed count
+        let estimate = estimate(&samples, 10, now).unwrap();
+        assert_eq!(estimate.minutes_remaining, Some(30));
+        assert_eq!(estimate.confidence, 0.33);
+    }
+
+    #[test]
+    fn test_estimate_charge() {
+        // A Pro Controller charging 25% every 20 minutes after running on battery
+        let samples = samples(&[
+            (0, 75, Status::Discharging),
+            (600, 50, Status::Discharging),
+            (700, 50, Status::Charging),
+            (1200, 75, Status::Charging),
+            (2400, 100, Status::Discharging),
+        ]);
+        assert!(estimate(&samples, 25, 2400).is_none());
+
+        let samples = &samples[..4];
+        // A single change isn't enough to know the rate
+        assert!(estimate(samples, 25, 1200).is_none());
+
+        let samples = super::tests::samples(&[
+            (0, 25, Status::Charging),
+            (600, 50, Status::Charging),
+            (1200, 50, Status::Charging),
+            (1800, 75, Status::Charging),
+        ]);
+        let estimate = estimate(&samples, 25, 1800).unwrap();
+        // 25% per 20 minutes, 75% is really 63%, 37% to go
+        assert_eq!(estimate.minutes_to_full, Some(30));
+        assert_eq!(estimate.minutes_remaining, None);
+        assert_eq!(estimate.confidence, 0.33);
+    }
+
+    #[test]
+    fn test_describe_remaining() {
+        assert_eq!(describe_remaining(3), "less than 5 minutes left");
+        assert_eq!(describe_remaining(24), "about 25 minutes left");
+        assert_eq!(describe_remaining(60), "about 1 hour left");
+        assert_eq!(describe_remaining(95), "about 1h30 left");
+        assert_eq!(describe_remaining(175), "about 3 hours left");
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    controller::Status,
+    estimate::MAX_GAP_SECS,
+    history::{ControllerInfo, Sample},
+};
+
+// A month needs at least a full cycle worth of discharge for its average runtime to mean anything
+const MIN_DISCHARGED_FOR_RUNTIME: u64 = 100;
+// How many of the first months with a runtime make up the baseline
+const BASELINE_MONTHS: usize = 3;
+// Runtime lost compared to the baseline before we recommend replacing the battery
+pub const DEGRADATION_THRESHOLD: f64 = 0.2;
+
+/// Battery usage of a controller during a calendar month (UTC)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MonthlyUsage {
+    /// Time spent running on battery
+    pub discharge_seconds: u64,
+    /// Capacity used, in percentage points
+    pub discharged: u64,
+    /// Times the controller started charging
+    pub charges: u32,
+}
+
+/// Usage of a controller per month, kept for as long as we know the controller unlike the
+/// samples of the history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerHealth {
+    #[serde(flatten)]
+    pub info: ControllerInfo,
+    /// Keyed by `YYYY-MM`
+    pub months: BTreeMap<String, MonthlyUsage>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    pub id: String,
+    #[serde(flatten)]
+    pub info: ControllerInfo,
+    /// Equivalent full charge cycles, i.e. the capacity used divided by 100%
+    pub cycles: f64,
+    pub charges: u32,
+    pub months: Vec<MonthReport>,
+    /// Best average runtime on a full charge of the first months
+    pub baseline_runtime_minutes: Option<u32>,
+    /// Average runtime on a full charge of the latest month with enough usage
+    pub current_runtime_minutes: Option<u32>,
+    /// Share of the baseline runtime that was lost, from 0 to 1
+    pub degradation: Option<f64>,
+    pub degraded: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MonthReport {
+    pub month: String,
+    pub cycles: f64,
+    pub charges: u32,
+    pub discharge_hours: f64,
+    pub average_runtime_minutes: Option<u32>,
+}
+
+impl ControllerHealth {
+    pub fn new(info: ControllerInfo) -> Self {
+        Self {
+            info,
+            months: BTreeMap::new(),
+        }
+    }
+
+    /// Accounts for the time between two consecutive samples. Returns whether the usage changed.
+    pub fn add(&mut self, previous: &Sample, sample: &Sample) -> bool {
+        let started_charging =
+            sample.status == Status::Charging && previous.status != Status::Charging;
+        let elapsed = sample.time.saturating_sub(previous.time);
+        let discharging = previous.status == Status::Discharging
+            && sample.status == Status::Discharging
+            && elapsed <= MAX_GAP_SECS;
+        if !started_charging && !discharging {
+            return false;
+        }
+
+        let usage = self.months.entry(month(sample.time)).or_default();
+        if started_charging {
+            usage.charges += 1;
+        }
+        if discharging {
+            usage.discharge_seconds += elapsed;
+            usage.discharged += previous.capacity.saturating_sub(sample.capacity) as u64;
+        }
+        true
+    }
+
+    pub fn report(&self, id: &str) -> HealthReport {
+        let months: Vec<MonthReport> = self
+            .months
+            .iter()
+            .map(|(month, usage)| MonthReport {
+                month: month.clone(),
+                cycles: round(usage.discharged as f64 / 100.0),
+                charges: usage.charges,
+                discharge_hours: round(usage.discharge_seconds as f64 / 3600.0),
+                average_runtime_minutes: runtime_minutes(usage),
+            })
+            .collect();
+
+        let runtimes: Vec<u32> = months
+            .iter()
+            .filter_map(|month| month.average_runtime_minutes)
+            .collect();
+        let baseline = runtimes.iter().take(BASELINE_MONTHS).max().copied();
+        let current = runtimes.last().copied();
+        // Comparing the baseline with itself says nothing
+        let degradation = match (baseline, current) {
+            (Some(baseline), Some(current)) if runtimes.len() > 1 && baseline > 0 => {
+                Some(round((1.0 - current as f64 / baseline as f64).max(0.0)))
+            }
+            _ => None,
+        };
+
+        let discharged: u64 = self.months.values().map(|usage| usage.discharged).sum();
+        HealthReport {
+            id: id.to_string(),
+            info: self.info.clone(),
+            cycles: round(discharged as f64 / 100.0),
+            charges: self.months.values().map(|usage| usage.charges).sum(),
+            months,
+            baseline_runtime_minutes: baseline,
+            current_runtime_minutes: current,
+            degradation,
+            degraded: degradation.is_some_and(|degradation| degradation >= DEGRADATION_THRESHOLD),
+        }
+    }
+}
+
+/// How long a full charge lasted on average during the month
+fn runtime_minutes(usage: &MonthlyUsage) -> Option<u32> {
+    if usage.discharged < MIN_DISCHARGED_FOR_RUNTIME {
+        return None;
+    }
+    let seconds = usage.discharge_seconds as f64 * 100.0 / usage.discharged as f64;
+    Some((seconds / 60.0).round() as u32)
+}
+
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// `YYYY-MM` of a Unix time, in UTC
+pub fn month(time: u64) -> String {
+    DateTime::from_timestamp(time as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        controller::Status,
+        history::{ControllerInfo, Sample},
+    };
+
+    use super::ControllerHealth;
+
+    const DAY: u64 = 86_400;
+    // 2026-01-01T00:00:00Z
+    const JANUARY: u64 = 1_767_225_600;
+
+    fn sample(time: u64, capacity: u8, status: Status) -> Sample {
+        Sample {
+            time,
+            capacity,
+            status,
+        }
+    }
+
+    /// A full discharge from 100% to 0%, losing 10% every `minutes_per_step`, then plugged in
+    fn cycle(health: &mut ControllerHealth, start: u64, minutes_per_step: u64) {
+        let mut previous = sample(start, 100, Status::Discharging);
+        let mut time = start;
+        for capacity in (0..=90).rev().step_by(10) {
+            // Readings every 5 minutes
+            for _ in 0..minutes_per_step / 5 {
+                time += 300;
+                let next = sample(time, capacity as u8, Status::Discharging);
+                health.add(&previous, &next);
+                previous = next;
+            }
+        }
+        health.add(&previous, &sample(time + 60, 0, Status::Charging));
+    }
+
+    #[test]
+    fn test_health_report() {
+        let info = ControllerInfo {
+            name: "DualSense".to_string(),
+            vendor_id: 0x054c,
+            product_id: 0x0ce6,
+        };
+        let mut health = ControllerHealth::new(info);
+
+        // Two cycles of 10 hours in January, then 7.5 hours in March
+        cycle(&mut health, JANUARY, 60);
+        cycle(&mut health, JANUARY + DAY, 60);
+        cycle(&mut health, JANUARY + 60 * DAY, 45);
+
+        let report = health.report("054c-0ce6");
+        assert_eq!(report.cycles, 3.0);
+        assert_eq!(report.charges, 3);
+        assert_eq!(report.months.len(), 2);
+        assert_eq!(report.months[0].month, "2026-01");
+        assert_eq!(report.months[0].cycles, 2.0);
+        assert_eq!(report.months[0].average_runtime_minutes, Some(600));
+        assert_eq!(report.baseline_runtime_minutes, Some(600));
+        assert_eq!(report.current_runtime_minutes, Some(450));
+        assert_eq!(report.degradation, Some(0.25));
+        assert!(report.degraded);
+
+        // A gap means the controller was off, it doesn't count as runtime
+        let mut health = ControllerHealth::new(report.info);
+        let previous = sample(JANUARY, 50, Status::Discharging);
+        assert!(!health.add(&previous, &sample(JANUARY + DAY, 40, Status::Discharging)));
+        let report = health.report("054c-0ce6");
+        assert_eq!(report.cycles, 0.0);
+        assert_eq!(report.degradation, None);
+        assert!(!report.degraded);
+    }
+}
@@ -0,0 +1,617 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    api,
+    controller::{Controller, Status},
+    estimate::{self, Estimate},
+    health::{ControllerHealth, HealthReport},
+};
+
+// A sample is written at least this often while a controller stays the same, and right away
+// when its capacity or status changes
+#[cfg(not(debug_assertions))]
+const HISTORY_INTERVAL: Duration = Duration::from_secs(60);
+#[cfg(debug_assertions)]
+const HISTORY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Retention limits, per controller
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const MAX_SAMPLES: usize = 50_000;
+// Files are compacted once they are this much over the limits, so that not every new sample
+// rewrites the whole file
+const COMPACT_SLACK_SAMPLES: usize = MAX_SAMPLES / 10;
+const COMPACT_SLACK_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Each sample is stored as a little endian u32 Unix time, the capacity and the status
+const SAMPLE_SIZE: usize = 6;
+const INDEX_FILE: &str = "controllers.json";
+const HEALTH_FILE: &str = "health.json";
+const EVENTS_FILE: &str = "events.json";
+// The health totals change with every sample but only matter in the long run, so they are saved
+// at most this often
+const HEALTH_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sample {
+    /// Unix time in seconds
+    pub time: u64,
+    pub capacity: u8,
+    pub status: Status,
+}
+
+/// Something that happened to a controller besides its battery readings
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEvent {
+    /// Unix time in seconds
+    pub time: u64,
+    pub kind: HistoryEventKind,
+    /// Battery level of the last reading before the event
+    pub capacity: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HistoryEventKind {
+    /// The controller disappeared while low on battery, see `alerts::ran_flat`
+    RanFlat,
+}
+
+/// What we remember about a controller besides its samples, so the history stays readable
+/// after the controller is gone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerInfo {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct History {
+    pub id: String,
+    #[serde(flatten)]
+    pub info: ControllerInfo,
+    pub samples: Vec<Sample>,
+    pub events: Vec<HistoryEvent>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistorySummary {
+    pub id: String,
+    #[serde(flatten)]
+    pub info: ControllerInfo,
+    pub samples: usize,
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+}
+
+struct ControllerHistory {
+    info: ControllerInfo,
+    samples: Vec<Sample>,
+    events: Vec<HistoryEvent>,
+}
+
+struct Health {
+    controllers: HashMap<String, ControllerHealth>,
+    /// Unix time of the last save, `None` when everything is saved
+    unsaved_since: Option<u64>,
+}
+
+/// Battery readings of every controller we've seen, keyed by `Controller::stable_id`. Each
+/// controller has an append-only file of fixed size samples in `dir`, and `controllers.json`
+/// maps the ids to names. The rare events, like a controller running flat, are all in
+/// `events.json`. The monthly usage of each controller outlives the samples and is kept
+/// in `health.json`.
+pub struct HistoryService {
+    dir: PathBuf,
+    controllers: Mutex<HashMap<String, ControllerHistory>>,
+    health: Mutex<Health>,
+}
+
+impl HistoryService {
+    pub async fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let index: HashMap<String, ControllerInfo> =
+            match tokio::fs::read(dir.join(INDEX_FILE)).await {
+                Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                    error!("Ignoring the history index, failed to parse it: {}", err);
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            };
+
+        let mut events: HashMap<String, Vec<HistoryEvent>> =
+            match tokio::fs::read(dir.join(EVENTS_FILE)).await {
+                Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                    error!("Ignoring the history events, failed to parse them: {}", err);
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            };
+
+        let mut controllers = HashMap::new();
+        for (id, info) in index {
+            let samples = match tokio::fs::read(samples_path(&dir, &id)).await {
+                Ok(contents) => decode_samples(&contents),
+                Err(err) => {
+                    debug!("No history for {}: {}", id, err);
+                    Vec::new()
+                }
+            };
+            let events = events.remove(&id).unwrap_or_default();
+            controllers.insert(
+                id,
+                ControllerHistory {
+                    info,
+                    samples,
+                    events,
+                },
+            );
+        }
+
+        let health: HashMap<String, ControllerHealth> =
+            match tokio::fs::read(dir.join(HEALTH_FILE)).await {
+                Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                    error!("Ignoring the battery health, failed to parse it: {}", err);
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            };
+
+        let service = Self {
+            dir,
+            controllers: Mutex::new(controllers),
+            health: Mutex::new(Health {
+                controllers: health,
+                unsaved_since: None,
+            }),
+        };
+        service.compact(unix_now()).await?;
+        Ok(service)
+    }
+
+    /// Records the controllers that changed since their last sample, or whose last sample is
+    /// older than `HISTORY_INTERVAL`
+    pub async fn record(&self, controllers: &[Controller]) -> Result<()> {
+        let now = unix_now();
+        let mut needs_compaction = false;
+        let mut index_changed = false;
+        let mut usage = Vec::new();
+        let mut histories = self.controllers.lock().await;
+
+        for controller in controllers {
+            let id = controller.stable_id();
+            let info = ControllerInfo {
+                name: controller.name.clone(),
+                vendor_id: controller.vendor_id,
+                product_id: controller.product_id,
+            };
+            let history = histories.entry(id.clone()).or_insert_with(|| {
+                index_changed = true;
+                ControllerHistory {
+                    info: info.clone(),
+                    samples: Vec::new(),
+                    events: Vec::new(),
+                }
+            });
+            if history.info != info {
+                history.info = info;
+                index_changed = true;
+            }
+
+            let sample = Sample {
+                time: now,
+                capacity: controller.capacity,
+                status: controller.status,
+            };
+            let unchanged = history.samples.last().is_some_and(|last| {
+                last.capacity == sample.capacity
+                    && last.status == sample.status
+                    && now < last.time + HISTORY_INTERVAL.as_secs()
+            });
+            if unchanged {
+                continue;
+            }
+
+            if let Some(previous) = history.samples.last() {
+                usage.push((id.clone(), history.info.clone(), *previous, sample));
+            }
+            history.samples.push(sample);
+            self.append(&id, &sample).await?;
+            needs_compaction |= history.samples.len() > MAX_SAMPLES + COMPACT_SLACK_SAMPLES
+                || history.samples[0].time + (MAX_AGE + COMPACT_SLACK_AGE).as_secs() < now;
+        }
+
+        if index_changed {
+            self.save_index(&histories).await?;
+        }
+        drop(histories);
+        if needs_compaction {
+            self.compact(now).await?;
+        }
+        self.record_health(usage, now).await
+    }
+
+    /// Records an event of a controller we have readings of
+    pub async fn record_event(&self, id: &str, event: HistoryEvent) -> Result<()> {
+        let mut histories = self.controllers.lock().await;
+        let Some(history) = histories.get_mut(id) else {
+            debug!(
+                "Not recording {:?} of unknown controller {}",
+                event.kind, id
+            );
+            return Ok(());
+        };
+        info!("Recording {:?} of {}", event.kind, id);
+        history.events.push(event);
+        self.save_events(&histories).await
+    }
+
+    async fn record_health(
+        &self,
+        usage: Vec<(String, ControllerInfo, Sample, Sample)>,
+        now: u64,
+    ) -> Result<()> {
+        let mut health = self.health.lock().await;
+        let mut new_controller = false;
+        for (id, info, previous, sample) in usage {
+            let controller = health.controllers.entry(id).or_insert_with(|| {
+                new_controller = true;
+                ControllerHealth::new(info.clone())
+            });
+            controller.info = info;
+            if controller.add(&previous, &sample) && health.unsaved_since.is_none() {
+                health.unsaved_since = Some(now);
+            }
+        }
+
+        let save_due = health
+            .unsaved_since
+            .is_some_and(|since| now >= since + HEALTH_SAVE_INTERVAL.as_secs());
+        if new_controller || save_due {
+            write_atomic(
+                &self.dir.join(HEALTH_FILE),
+                &serde_json::to_vec(&health.controllers)?,
+            )
+            .await?;
+            health.unsaved_since = None;
+        }
+        Ok(())
+    }
+
+    /// Charge cycles and runtime per month of a controller, `None` if it never ran on battery
+    /// or charged while we watched
+    pub async fn health(&self, id: &str) -> Option<HealthReport> {
+        let health = self.health.lock().await;
+        Some(health.controllers.get(id)?.report(id))
+    }
+
+    /// Samples and events of a controller at or after `since`, `None` if we never saw it
+    pub async fn history(&self, id: &str, since: Option<u64>) -> Option<History> {
+        let histories = self.controllers.lock().await;
+        let history = histories.get(id)?;
+        let since = since.unwrap_or_default();
+        // Samples are in the order they were recorded, which isn't the order of their times
+        // when the clock went back, so all of them are checked
+        Some(History {
+            id: id.to_string(),
+            info: history.info.clone(),
+            samples: history
+                .samples
+                .iter()
+                .filter(|sample| sample.time >= since)
+                .copied()
+                .collect(),
+            events: history
+                .events
+                .iter()
+                .filter(|event| event.time >= since)
+                .copied()
+                .collect(),
+        })
+    }
+
+    /// Time left estimates of the controllers, keyed by their stable id. Controllers without
+    /// enough history are left out.
+    pub async fn estimates(&self, controllers: &[Controller]) -> HashMap<String, Estimate> {
+        let now = unix_now();
+        let histories = self.controllers.lock().await;
+        controllers
+            .iter()
+            .filter_map(|controller| {
+                let id = controller.stable_id();
+                let samples = &histories.get(&id)?.samples;
+                let estimate =
+                    estimate::estimate(samples, api::capacity_step(controller.vendor_id), now)?;
+                Some((id, estimate))
+            })
+            .collect()
+    }
+
+    pub async fn summaries(&self) -> Vec<HistorySummary> {
+        let histories = self.controllers.lock().await;
+        let mut summaries: Vec<_> = histories
+            .iter()
+            .map(|(id, history)| HistorySummary {
+                id: id.clone(),
+                info: history.info.clone(),
+                samples: history.samples.len(),
+                first: history.samples.first().map(|sample| sample.time),
+                last: history.samples.last().map(|sample| sample.time),
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.id.cmp(&b.id));
+        summaries
+    }
+
+    /// Drops the samples and events that are past the retention limits and rewrites the files
+    /// that changed. Controllers left without samples are forgotten.
+    async fn compact(&self, now: u64) -> Result<()> {
+        let oldest = now.saturating_sub(MAX_AGE.as_secs());
+        let mut histories = self.controllers.lock().await;
+        let mut index_changed = false;
+        let mut events_changed = false;
+
+        let ids: Vec<String> = histories.keys().cloned().collect();
+        for id in ids {
+            let history = histories.get_mut(&id).unwrap();
+            let events = history.events.len();
+            history.events.retain(|event| event.time >= oldest);
+            events_changed |= history.events.len() != events;
+            let before = history.samples.len();
+            history.samples.retain(|sample| sample.time >= oldest);
+            let excess = history.samples.len().saturating_sub(MAX_SAMPLES);
+            history.samples.drain(..excess);
+
+            if history.samples.is_empty() {
+                info!("Forgetting the history of {}", id);
+                let history = histories.remove(&id).unwrap();
+                let _ = tokio::fs::remove_file(samples_path(&self.dir, &id)).await;
+                index_changed = true;
+                events_changed |= !history.events.is_empty();
+            } else if history.samples.len() != before {
+                debug!(
+                    "Compacting the history of {}, {} samples dropped",
+                    id,
+                    before - history.samples.len()
+                );
+                let contents: Vec<u8> = history.samples.iter().flat_map(encode_sample).collect();
+                write_atomic(&samples_path(&self.dir, &id), &contents).await?;
+            }
+        }
+
+        if index_changed {
+            self.save_index(&histories).await?;
+        }
+        if events_changed {
+            self.save_events(&histories).await?;
+        }
+        Ok(())
+    }
+
+    async fn append(&self, id: &str, sample: &Sample) -> Result<()> {
+        let path = samples_path(&self.dir, id);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        file.write_all(&encode_sample(sample)).await?;
+        Ok(())
+    }
+
+    async fn save_index(&self, histories: &HashMap<String, ControllerHistory>) -> Result<()> {
+        let index: HashMap<&String, &ControllerInfo> = histories
+            .iter()
+            .map(|(id, history)| (id, &history.info))
+            .collect();
+        write_atomic(&self.dir.join(INDEX_FILE), &serde_json::to_vec(&index)?).await
+    }
+
+    async fn save_events(&self, histories: &HashMap<String, ControllerHistory>) -> Result<()> {
+        let events: HashMap<&String, &Vec<HistoryEvent>> = histories
+            .iter()
+            .filter(|(_, history)| !history.events.is_empty())
+            .map(|(id, history)| (id, &history.events))
+            .collect();
+        write_atomic(&self.dir.join(EVENTS_FILE), &serde_json::to_vec(&events)?).await
+    }
+}
+
+/// Samples as CSV, one line per sample after a header
+pub fn to_csv(samples: &[Sample]) -> String {
+    let mut csv = String::from("time,capacity,status\n");
+    for sample in samples {
+        let status = match sample.status {
+            Status::Charging => "charging",
+            Status::Discharging => "discharging",
+            Status::Unknown => "unknown",
+        };
+        csv.push_str(&format!("{},{},{}\n", sample.time, sample.capacity, status));
+    }
+    csv
+}
+
+fn samples_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.bin", id))
+}
+
+fn encode_sample(sample: &Sample) -> [u8; SAMPLE_SIZE] {
+    let time = u32::try_from(sample.time).unwrap_or(u32::MAX).to_le_bytes();
+    let status = match sample.status {
+        Status::Unknown => 0,
+        Status::Charging => 1,
+        Status::Discharging => 2,
+    };
+    [time[0], time[1], time[2], time[3], sample.capacity, status]
+}
+
+/// Decodes a samples file. A trailing partial sample, e.g. from a crash in the middle of an
+/// append, is ignored.
+fn decode_samples(contents: &[u8]) -> Vec<Sample> {
+    contents
+        .chunks_exact(SAMPLE_SIZE)
+        .map(|chunk| Sample {
+            time: u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64,
+            capacity: chunk[4],
+            status: match chunk[5] {
+                1 => Status::Charging,
+                2 => Status::Discharging,
+                _ => Status::Unknown,
+            },
+        })
+        .collect()
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    let tmp_path = path.with_file_name(file_name);
+
+    let mut file = tokio::fs::File::create(&tmp_path)
+        .await
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::{Controller, Status};
+
+    use super::{
+        decode_samples, encode_sample, to_csv, HistoryEvent, HistoryEventKind, HistoryService,
+        Sample,
+    };
+
+    fn controller(capacity: u8, status: Status) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity,
+            status,
+            bluetooth: true,
+            serial_number: Some("a0:ab:51:12:34:56".to_string()),
+            device_path: Some("/dev/hidraw3".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_encode_samples() {
+        let samples = vec![
+            Sample {
+                time: 1_700_000_000,
+                capacity: 55,
+                status: Status::Discharging,
+            },
+            Sample {
+                time: 1_700_000_060,
+                capacity: 100,
+                status: Status::Charging,
+            },
+        ];
+        let mut contents: Vec<u8> = samples.iter().flat_map(encode_sample).collect();
+        assert_eq!(decode_samples(&contents), samples);
+
+        // A partial sample at the end is ignored
+        contents.extend([1, 2, 3]);
+        assert_eq!(decode_samples(&contents), samples);
+
+        assert_eq!(
+            to_csv(&samples),
+            "time,capacity,status\n1700000000,55,discharging\n1700000060,100,charging\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_history() {
+        let dir =
+            std::env::temp_dir().join(format!("controller-tools-history-{}", std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        let id = "054c-0ce6-a0-ab-51-12-34-56";
+
+        let service = HistoryService::new(&dir).await.unwrap();
+        service
+            .record(&[controller(55, Status::Discharging)])
+            .await
+            .unwrap();
+        // Same reading right away, not recorded
+        service
+            .record(&[controller(55, Status::Discharging)])
+            .await
+            .unwrap();
+        service
+            .record(&[controller(55, Status::Charging)])
+            .await
+            .unwrap();
+
+        let history = service.history(id, None).await.unwrap();
+        assert_eq!(history.info.name, "DualSense");
+        assert_eq!(history.samples.len(), 2);
+        assert_eq!(history.samples[1].status, Status::Charging);
+        assert!(service.history("unknown", None).await.is_none());
+        let since = history.samples[0].time;
+        assert_eq!(
+            service.history(id, Some(since)).await.unwrap().samples,
+            history.samples
+        );
+        let since = history.samples[1].time + 1;
+        assert!(service
+            .history(id, Some(since))
+            .await
+            .unwrap()
+            .samples
+            .is_empty());
+
+        let event = HistoryEvent {
+            time: history.samples[1].time,
+            kind: HistoryEventKind::RanFlat,
+            capacity: 5,
+        };
+        service.record_event(id, event).await.unwrap();
+        service.record_event("unknown", event).await.unwrap();
+
+        // The history survives a restart
+        drop(service);
+        let service = HistoryService::new(&dir).await.unwrap();
+        let summaries = service.summaries().await;
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].id, id);
+        assert_eq!(summaries[0].samples, 2);
+        assert_eq!(service.history(id, None).await.unwrap().events, [event]);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,105 +1,402 @@
+mod alerts;
 mod api;
+mod bluez;
+mod capture;
+mod cli;
 mod controller;
+mod dbus;
+mod desktop;
+mod estimate;
+mod health;
+mod history;
+mod metrics;
+mod monitor;
+mod mqtt;
+mod protocol;
 mod settings;
+mod webhook;
 mod ws;
 
 use std::{fs::File, net::SocketAddr, sync::Arc};
 
+use anyhow::{anyhow, Context, Result};
 use axum::{
-    http::{HeaderValue, Method, StatusCode},
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
-use controller::Controller;
-use log::info;
+use clap::Parser;
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::Value;
 use simplelog::{
-    ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger,
+    ColorChoice, CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger, TerminalMode,
+    WriteLogger,
 };
 
+use tokio::sync::{mpsc, watch};
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::settings::SettingsService;
+use crate::alerts::AlertService;
+use crate::api::fake::{self, Scenario, ScenarioState};
+use crate::cli::{Cli, Command, ServeArgs};
+use crate::controller::Controller;
+use crate::health::HealthReport;
+use crate::history::{HistoryService, HistorySummary};
+use crate::monitor::RefreshRequest;
+use crate::protocol::ControllerState;
+use crate::settings::{PatchError, Settings, SettingsService};
 
 const PORT: u16 = 33220;
 
 pub struct AppState {
     settings_service: SettingsService,
+    history_service: HistoryService,
+    alert_service: AlertService,
+    /// Controllers found by the monitor's latest check
+    controllers: watch::Sender<Vec<Controller>>,
+    /// Asks the monitor to check the controllers without waiting for its next tick, the result of
+    /// the check is sent back
+    refresh: mpsc::UnboundedSender<RefreshRequest>,
 }
 
 #[tokio::main]
-async fn main() {
-    let args: Vec<String> = std::env::args().collect();
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if let Some(scenario) = &cli.scenario {
+        fake::start(fake::load(scenario)?)?;
+    }
 
-    if args.len() != 3 {
-        panic!("Error: Expected 2 arguments, but got {}", args.len() - 1);
+    match cli.command {
+        None => serve(&cli, &ServeArgs::default()).await,
+        Some(Command::Serve(ref args)) => serve(&cli, args).await,
+        Some(Command::List(ref args)) => {
+            // Keep stdout for the output, only warnings and errors go to stderr by default
+            let level_filter = cli.log_level.unwrap_or(LevelFilter::Warn);
+            init_logging(&cli, level_filter, TerminalMode::Stderr)?;
+            cli::list(args).await
+        }
+        Some(Command::Watch(ref args)) => {
+            // Keep stdout for the output, only warnings and errors go to stderr by default
+            let level_filter = cli.log_level.unwrap_or(LevelFilter::Warn);
+            init_logging(&cli, level_filter, TerminalMode::Stderr)?;
+            cli::watch(args).await
+        }
+        Some(Command::Capture(ref args)) => {
+            let level_filter = cli.log_level.unwrap_or(LevelFilter::Warn);
+            init_logging(&cli, level_filter, TerminalMode::Stderr)?;
+            cli::capture(args).await
+        }
     }
+}
 
-    let settings_directory = args[1].clone();
+async fn serve(cli: &Cli, args: &ServeArgs) -> Result<()> {
+    let settings_service = SettingsService::new(&cli.settings).await?;
 
-    let settings_location = match tokio::fs::metadata(&settings_directory).await {
-        Ok(_) => format!("{}/settings.json", &settings_directory),
-        Err(_) => String::from("/tmp/controller-tools.json"),
-    };
-    let settings_service = SettingsService::new(&settings_location).await.unwrap();
+    let level_filter = cli
+        .log_level
+        .unwrap_or(log_level(settings_service.get_settings().await.debug));
+    init_logging(cli, level_filter, TerminalMode::Mixed)?;
 
-    let level_filter = match settings_service.get_settings().await.debug {
-        true => LevelFilter::Debug,
-        false => LevelFilter::Info,
-    };
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            level_filter,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            level_filter,
-            Config::default(),
-            File::create(args[2].clone()).unwrap(),
-        ),
-    ])
-    .unwrap();
+    let history_service = HistoryService::new(cli.data_dir.join("history")).await?;
+    let alert_service = AlertService::new(cli.data_dir.join("alerts.json")).await?;
+    let (refresh, refresh_requests) = mpsc::unbounded_channel();
+    let app_state = Arc::new(AppState {
+        settings_service,
+        history_service,
+        alert_service,
+        controllers: watch::Sender::new(Vec::new()),
+        refresh,
+    });
+
+    // Keep recording the battery history and sending alerts while no client is connected
+    tokio::spawn(monitor::run(app_state.clone(), refresh_requests));
+
+    let webhook_state = app_state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = webhook::run(webhook_state).await {
+            error!("Stopped calling webhooks: {:#}", err);
+        }
+    });
+
+    let mqtt_state = app_state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = mqtt::run(mqtt_state).await {
+            error!("Stopped publishing to MQTT: {:#}", err);
+        }
+    });
+
+    let bluez_state = app_state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = bluez::provide_batteries(bluez_state).await {
+            error!("Stopped providing batteries to BlueZ: {:#}", err);
+        }
+    });
+
+    let desktop_state = app_state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = desktop::run(desktop_state).await {
+            error!("Stopped showing desktop notifications: {:#}", err);
+        }
+    });
+
+    if let Some(bus) = args.dbus {
+        let dbus_state = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = dbus::serve(dbus_state, bus).await {
+                error!("Stopped the D-Bus service: {:#}", err);
+            }
+        });
+    }
+
+    // Pick up changes made to the config file by the frontend
+    let watch_state = app_state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = watch_state.settings_service.watch_file().await {
+            error!("Stopped watching config file: {:#}", err);
+        }
+    });
 
-    let app_state = Arc::new(AppState { settings_service });
+    // An explicit --log-level wins over the debug setting
+    if cli.log_level.is_none() {
+        let mut settings = app_state.settings_service.subscribe();
+        tokio::spawn(async move {
+            while settings.changed().await.is_ok() {
+                let level_filter = log_level(settings.borrow_and_update().debug);
+                if level_filter != log::max_level() {
+                    log::set_max_level(level_filter);
+                    info!("Logging level: {:?}", level_filter);
+                }
+            }
+        });
+    }
 
     let app = Router::new()
         .route("/controllers", get(controllers_json))
-        .route("/ws", get(ws::ws_handler))
-        .with_state(app_state)
-        .layer(
-            CorsLayer::new()
-                .allow_origin("https://steamloopback.host".parse::<HeaderValue>().unwrap())
-                .allow_headers(Any)
-                .allow_methods([Method::GET, Method::POST]),
-        );
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], PORT));
+        .route("/controllers/:id/history", get(get_history))
+        .route("/controllers/:id/health", get(get_health))
+        .route("/history", get(get_history_summaries))
+        .route(
+            "/settings",
+            get(get_settings).put(put_settings).patch(patch_settings),
+        )
+        .route("/metrics", get(get_metrics))
+        .route("/ws", get(ws::ws_handler));
+    // Fake controllers are a development tool, release builds only accept them from --scenario
+    let app = if cfg!(debug_assertions) || cli.scenario.is_some() {
+        app.route(
+            "/fake/scenario",
+            get(get_scenario).put(put_scenario).delete(delete_scenario),
+        )
+    } else {
+        app
+    };
+    let app = app.with_state(app_state).layer(
+        CorsLayer::new()
+            .allow_origin("https://steamloopback.host".parse::<HeaderValue>().unwrap())
+            .allow_headers(Any)
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ]),
+    );
+
+    let addr = SocketAddr::new(args.bind, args.port);
     info!("Logging level: {:?}", level_filter);
     info!("Listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Logs to the terminal and, if requested, to the log file. The loggers let everything through,
+/// the actual level is set with `log::set_max_level` so it can follow the debug setting at
+/// runtime.
+fn init_logging(cli: &Cli, level_filter: LevelFilter, terminal_mode: TerminalMode) -> Result<()> {
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        LevelFilter::Trace,
+        Config::default(),
+        terminal_mode,
+        ColorChoice::Auto,
+    )];
+    if let Some(log_file) = &cli.log_file {
+        let file = File::create(log_file)
+            .with_context(|| format!("Failed to create log file {}", log_file.display()))?;
+        loggers.push(WriteLogger::new(
+            LevelFilter::Trace,
+            Config::default(),
+            file,
+        ));
+    }
+    CombinedLogger::init(loggers)?;
+    log::set_max_level(level_filter);
+    Ok(())
+}
+
+fn log_level(debug: bool) -> LevelFilter {
+    match debug {
+        true => LevelFilter::Debug,
+        false => LevelFilter::Info,
+    }
 }
 
-async fn controllers_json() -> Result<Json<Vec<Controller>>, AppError> {
+async fn controllers_json(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ControllerState>>, AppError> {
     // Spawn a tokio blocking task because `get_controllers()` is a blocking API
     let controllers = tokio::task::spawn_blocking(api::controllers).await??;
+    let estimates = state.history_service.estimates(&controllers).await;
+    let controllers = controllers
+        .iter()
+        .map(|controller| ControllerState {
+            estimate: estimates.get(&controller.stable_id()).copied(),
+            ..controller.into()
+        })
+        .collect();
     Ok(Json(controllers))
 }
 
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Unix time in seconds
+    since: Option<u64>,
+    #[serde(default)]
+    format: HistoryFormat,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum HistoryFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+async fn get_history(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Response, AppError> {
+    let history = state
+        .history_service
+        .history(&id, query.since)
+        .await
+        .ok_or_else(|| {
+            AppError::new(StatusCode::NOT_FOUND, anyhow!("Unknown controller {}", id))
+        })?;
+    Ok(match query.format {
+        HistoryFormat::Json => Json(history).into_response(),
+        HistoryFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.csv\"", id),
+                ),
+            ],
+            history::to_csv(&history.samples),
+        )
+            .into_response(),
+    })
+}
+
+async fn get_health(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<HealthReport>, AppError> {
+    let report = state.history_service.health(&id).await.ok_or_else(|| {
+        AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow!("No battery health for {}", id),
+        )
+    })?;
+    Ok(Json(report))
+}
+
+async fn get_history_summaries(State(state): State<Arc<AppState>>) -> Json<Vec<HistorySummary>> {
+    Json(state.history_service.summaries().await)
+}
+
+async fn get_metrics() -> Result<impl IntoResponse, AppError> {
+    let metrics = metrics::global().render()?;
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics))
+}
+
+async fn get_settings(State(state): State<Arc<AppState>>) -> Json<Settings> {
+    Json(state.settings_service.get_settings().await.redacted())
+}
+
+async fn put_settings(
+    State(state): State<Arc<AppState>>,
+    Json(mut settings): Json<Settings>,
+) -> Result<Json<Settings>, AppError> {
+    // The schema version is ours to manage
+    settings.version = state.settings_service.get_settings().await.version;
+    let settings = state.settings_service.set_settings(settings).await?;
+    Ok(Json(settings.redacted()))
+}
+
+async fn patch_settings(
+    State(state): State<Arc<AppState>>,
+    Json(patch): Json<Value>,
+) -> Result<Json<Settings>, AppError> {
+    match state.settings_service.patch_settings(&patch).await {
+        Ok(settings) => Ok(Json(settings.redacted())),
+        Err(PatchError::Invalid(err)) => Err(AppError::new(StatusCode::UNPROCESSABLE_ENTITY, err)),
+        Err(PatchError::Save(err)) => Err(err.into()),
+    }
+}
+
+async fn get_scenario() -> Result<Json<ScenarioState>, AppError> {
+    fake::state()
+        .map(Json)
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("No scenario is running")))
+}
+
+async fn put_scenario(Json(scenario): Json<Scenario>) -> Result<Json<ScenarioState>, AppError> {
+    fake::start(scenario).map_err(|err| AppError::new(StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    info!("Started a fake controller scenario");
+    get_scenario().await
+}
+
+async fn delete_scenario() -> StatusCode {
+    fake::reset();
+    info!("Stopped the fake controller scenario");
+    StatusCode::NO_CONTENT
+}
+
 // Make our own error that wraps `anyhow::Error`
-struct AppError(anyhow::Error);
+struct AppError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl AppError {
+    fn new(status: StatusCode, error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            status,
+            error: error.into(),
+        }
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let message = if self.status.is_server_error() {
+            format!("Something went wrong: {}", self.error)
+        } else {
+            self.error.to_string()
+        };
+        (self.status, message).into_response()
     }
 }
 
@@ -110,6 +407,6 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, err)
     }
 }
@@ -0,0 +1,204 @@
+use std::{sync::LazyLock, time::Instant};
+
+use anyhow::Result;
+use prometheus::{
+    GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::controller::{Controller, Status};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The metrics served on `/metrics`, shared by everything that probes controllers
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    battery: GaugeVec,
+    charging: GaugeVec,
+    probe_duration: HistogramVec,
+    probe_errors: IntCounterVec,
+    websocket_clients: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let controller_labels = ["id", "name", "transport"];
+        let battery = GaugeVec::new(
+            Opts::new(
+                "controller_battery_percent",
+                "Battery level of the controller in percent",
+            ),
+            &controller_labels,
+        )
+        .unwrap();
+        let charging = GaugeVec::new(
+            Opts::new(
+                "controller_charging",
+                "Whether the controller is charging (1) or not (0)",
+            ),
+            &controller_labels,
+        )
+        .unwrap();
+        let probe_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "controller_probe_duration_seconds",
+                "Time spent reading the battery of a controller",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]),
+            &["driver"],
+        )
+        .unwrap();
+        let probe_errors = IntCounterVec::new(
+            Opts::new(
+                "controller_probe_errors_total",
+                "Failed attempts to read the battery of a controller",
+            ),
+            &["vendor_id", "product_id"],
+        )
+        .unwrap();
+        let websocket_clients =
+            IntGauge::new("websocket_clients", "WebSocket clients currently connected").unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(battery.clone())).unwrap();
+        registry.register(Box::new(charging.clone())).unwrap();
+        registry.register(Box::new(probe_duration.clone())).unwrap();
+        registry.register(Box::new(probe_errors.clone())).unwrap();
+        registry
+            .register(Box::new(websocket_clients.clone()))
+            .unwrap();
+        Self {
+            registry,
+            battery,
+            charging,
+            probe_duration,
+            probe_errors,
+            websocket_clients,
+        }
+    }
+
+    /// Replaces the controller gauges with the controllers found by the latest probe, so that
+    /// disconnected controllers stop being reported
+    pub fn record_controllers(&self, controllers: &[Controller]) {
+        self.battery.reset();
+        self.charging.reset();
+        for controller in controllers {
+            let id = controller.stable_id();
+            let transport = if controller.bluetooth {
+                "bluetooth"
+            } else {
+                "usb"
+            };
+            let labels = [id.as_str(), controller.name.as_str(), transport];
+            self.battery
+                .with_label_values(&labels)
+                .set(controller.capacity as f64);
+            let charging = controller.status == Status::Charging;
+            self.charging
+                .with_label_values(&labels)
+                .set(if charging { 1.0 } else { 0.0 });
+        }
+    }
+
+    /// Times a probe of a device with the given driver and counts it if it fails
+    pub fn probe<T>(
+        &self,
+        driver: &str,
+        vendor_id: u16,
+        product_id: u16,
+        probe: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = probe();
+        self.probe_duration
+            .with_label_values(&[driver])
+            .observe(start.elapsed().as_secs_f64());
+        if result.is_err() {
+            let vendor_id = format!("{:04x}", vendor_id);
+            let product_id = format!("{:04x}", product_id);
+            self.probe_errors
+                .with_label_values(&[&vendor_id, &product_id])
+                .inc();
+        }
+        result
+    }
+
+    /// Counts a WebSocket client for as long as the returned guard lives
+    pub fn websocket_client(&self) -> WebSocketClient {
+        self.websocket_clients.inc();
+        WebSocketClient(self.websocket_clients.clone())
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> Result<String> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+pub struct WebSocketClient(IntGauge);
+
+impl Drop for WebSocketClient {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::Metrics;
+    use crate::controller::{Controller, Status};
+
+    fn controller(name: &str, status: Status, bluetooth: bool) -> Controller {
+        Controller {
+            name: name.to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity: 40,
+            status,
+            bluetooth,
+            serial_number: Some(name.to_lowercase()),
+            device_path: None,
+        }
+    }
+
+    #[test]
+    fn test_metrics() -> anyhow::Result<()> {
+        let metrics = Metrics::new();
+        metrics.record_controllers(&[
+            controller("Left", Status::Charging, false),
+            controller("Right", Status::Discharging, true),
+        ]);
+        metrics.record_controllers(&[controller("Right", Status::Discharging, true)]);
+        metrics.probe("playstation", 0x054c, 0x0ce6, || Ok(()))?;
+        let failed = metrics.probe("playstation", 0x054c, 0x0ce6, || -> anyhow::Result<()> {
+            Err(anyhow!("Timed out"))
+        });
+        assert!(failed.is_err());
+        let client = metrics.websocket_client();
+        let _other = metrics.websocket_client();
+        drop(client);
+
+        let text = metrics.render()?;
+        assert!(text.contains(
+            r#"controller_battery_percent{id="054c-0ce6-right",name="Right",transport="bluetooth"} 40"#
+        ));
+        assert!(text.contains(
+            r#"controller_charging{id="054c-0ce6-right",name="Right",transport="bluetooth"} 0"#
+        ));
+        // Gone with the latest probe
+        assert!(!text.contains(r#"name="Left""#));
+        assert!(text.contains(r#"controller_probe_duration_seconds_count{driver="playstation"} 2"#));
+        assert!(
+            text.contains(r#"controller_probe_errors_total{product_id="0ce6",vendor_id="054c"} 1"#)
+        );
+        assert!(text.contains("websocket_clients 1"));
+        Ok(())
+    }
+}
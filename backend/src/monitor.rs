@@ -0,0 +1,332 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use chrono::Local;
+use log::{debug, error, info};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    alerts::{self, unix_now, AlertState},
+    api,
+    bluez::DisconnectWatcher,
+    controller::Controller,
+    history::{HistoryEvent, HistoryEventKind},
+    protocol::{AlertGroup, BatteryAlert, ErrorCode, ServerMessage},
+    settings::Settings,
+    AppState,
+};
+
+// How often to check the battery level
+#[cfg(not(debug_assertions))]
+pub const BATTERY_CHECK_INTERVAL: Duration = std::time::Duration::from_secs(60);
+#[cfg(debug_assertions)]
+pub const BATTERY_CHECK_INTERVAL: Duration = std::time::Duration::from_secs(10);
+
+/// The controllers found by a check, or why it failed
+pub type CheckResult = Result<Vec<Controller>, String>;
+
+/// Where the monitor sends the result of a check a client asked for
+pub type RefreshRequest = oneshot::Sender<CheckResult>;
+
+/// Checks the controllers in the background, whether or not a client is connected: records the
+/// battery history and publishes the alerts, or the error of a failed check, to the alert
+/// service's subscribers
+pub async fn run(
+    state: Arc<AppState>,
+    mut refresh_requests: mpsc::UnboundedReceiver<RefreshRequest>,
+) {
+    let mut settings = state.settings_service.subscribe();
+    let mut notifications = settings.borrow().notifications;
+    let mut interval = tokio::time::interval(BATTERY_CHECK_INTERVAL);
+    let mut previous: Option<Vec<Controller>> = None;
+    let disconnects = DisconnectWatcher::system().await;
+    let mut waiting = Vec::new();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            Some(request) = refresh_requests.recv() => {
+                debug!("Checking controllers on request");
+                waiting.push(request);
+                interval.reset();
+            }
+            Ok(()) = settings.changed() => {
+                let enabled = settings.borrow_and_update().notifications;
+                let just_enabled = enabled && !notifications;
+                notifications = enabled;
+                if !just_enabled {
+                    continue;
+                }
+                // Don't make the user wait a full interval for alerts they just turned on
+                debug!("Notifications enabled, checking controllers now");
+                interval.reset();
+            }
+        }
+
+        // Requests made before the check starts are answered by it
+        while let Ok(request) = refresh_requests.try_recv() {
+            waiting.push(request);
+        }
+        let result = match check(&state, previous.as_deref(), &disconnects).await {
+            Ok(controllers) => {
+                state.controllers.send_replace(controllers.clone());
+                previous = Some(controllers.clone());
+                Ok(controllers)
+            }
+            Err(err) => {
+                error!("Error getting controllers: {:#}", err);
+                let message = ServerMessage::error(ErrorCode::ProbeFailed, err.to_string());
+                state.alert_service.publish(vec![message]);
+                Err(err.to_string())
+            }
+        };
+        for request in waiting.drain(..) {
+            // The client may be gone already
+            let _ = request.send(result.clone());
+        }
+    }
+}
+
+async fn check(
+    state: &AppState,
+    previous: Option<&[Controller]>,
+    disconnects: &DisconnectWatcher,
+) -> Result<Vec<Controller>> {
+    let controllers = api::controllers_async().await?;
+    if let Err(err) = state.history_service.record(&controllers).await {
+        error!("Failed to record the battery history: {:#}", err);
+    }
+
+    let settings = state.settings_service.get_settings().await;
+    let flat = match previous {
+        Some(previous) => alerts::ran_flat(previous, &controllers, &settings, |controller| {
+            disconnects.requested(controller)
+        }),
+        None => Vec::new(),
+    };
+    for controller in &flat {
+        info!("{} likely ran out of battery", controller.name);
+        let event = HistoryEvent {
+            time: unix_now(),
+            kind: HistoryEventKind::RanFlat,
+            capacity: controller.capacity,
+        };
+        if let Err(err) = state
+            .history_service
+            .record_event(&controller.stable_id(), event)
+            .await
+        {
+            error!("Failed to record the battery history: {:#}", err);
+        }
+    }
+
+    if !settings.notifications {
+        debug!("Notifications disabled, skipping notification check...");
+        return Ok(controllers);
+    }
+    let estimates = state.history_service.estimates(&controllers).await;
+    let messages = state
+        .alert_service
+        .update(|alerts| {
+            let quiet = quiet(&settings, alerts);
+            let mut messages = match previous {
+                Some(previous) => {
+                    alerts::transition_alerts(previous, &controllers, &flat, &settings, alerts)
+                }
+                None => Vec::new(),
+            };
+            messages.extend(alerts::battery_alerts(
+                previous,
+                &controllers,
+                &estimates,
+                &settings,
+                alerts,
+            ));
+            alerts::hold_alerts(messages, quiet, settings.quiet.suppressed, alerts)
+        })
+        .await;
+    // One notification for everything that happened in this check
+    let messages = group_alerts(messages, &settings);
+    state.alert_service.publish(messages);
+    Ok(controllers)
+}
+
+/// Whether alerts are held back right now, because of the quiet hours or a snooze of everything
+fn quiet(settings: &Settings, alerts: &mut AlertState) -> bool {
+    let now = Local::now();
+    if alerts
+        .snoozed_all_until
+        .is_some_and(|until| until <= now.timestamp() as u64)
+    {
+        alerts.snoozed_all_until = None;
+    }
+    alerts.snoozed_all_until.is_some() || settings.quiet.is_quiet(&now)
+}
+
+/// Replaces the alerts by a single group when there's more than one, e.g. "2 controllers low:
+/// DualSense 15%, Pro Controller 5%". Controllers go by their nickname in the settings if they
+/// have one. Other messages are left as they are.
+pub fn group_alerts(messages: Vec<ServerMessage>, settings: &Settings) -> Vec<ServerMessage> {
+    if messages.iter().filter_map(ServerMessage::alert).count() < 2 {
+        return messages;
+    }
+
+    let mut grouped = Vec::new();
+    let mut low = Vec::new();
+    let mut charged = Vec::new();
+    let mut others = Vec::new();
+    let mut alert_messages = Vec::new();
+    let mut position = None;
+    for message in messages {
+        if message.alert().is_none() {
+            grouped.push(message);
+            continue;
+        }
+        position.get_or_insert(grouped.len());
+        alert_messages.push(message.clone());
+        match message {
+            ServerMessage::LowBattery(alert) => low.push(alert),
+            ServerMessage::Charged(alert) => charged.push(alert),
+            message => others.extend(message.alert().cloned()),
+        }
+    }
+
+    let name = |alert: &BatteryAlert| {
+        settings
+            .nickname(&alert.stable_id)
+            .unwrap_or(&alert.name)
+            .to_string()
+    };
+    let message = if others.is_empty() && charged.is_empty() {
+        let controllers: Vec<String> = low
+            .iter()
+            .map(|alert| format!("{} {}%", name(alert), alert.capacity))
+            .collect();
+        format!("{} controllers low: {}", low.len(), controllers.join(", "))
+    } else if others.is_empty() && low.is_empty() {
+        let controllers: Vec<String> = charged.iter().map(name).collect();
+        format!(
+            "{} controllers fully charged: {}",
+            charged.len(),
+            controllers.join(", ")
+        )
+    } else {
+        let texts: Vec<&str> = low
+            .iter()
+            .chain(&charged)
+            .chain(&others)
+            .map(|alert| alert.message.as_str())
+            .collect();
+        texts.join("; ")
+    };
+    let alerts: Vec<BatteryAlert> = low.into_iter().chain(charged).chain(others).collect();
+    let group = AlertGroup {
+        message,
+        critical: alerts.iter().any(|alert| alert.critical),
+        alerts,
+        messages: alert_messages,
+    };
+    grouped.insert(
+        position.unwrap_or_default(),
+        ServerMessage::AlertGroup(group),
+    );
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::group_alerts;
+    use crate::alerts::{battery_alerts, AlertState};
+    use crate::controller::{Controller, Status};
+    use crate::protocol::ServerMessage;
+    use crate::settings::{ControllerSettings, Settings};
+
+    fn controller(path: &str, capacity: u8, status: Status) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity,
+            status,
+            bluetooth: true,
+            serial_number: Some(path.trim_start_matches("/dev/").to_string()),
+            device_path: Some(path.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_group_alerts() {
+        let mut settings = Settings::default();
+        settings.controllers.insert(
+            "054c-0ce6-hidraw2".to_string(),
+            ControllerSettings {
+                nickname: Some("Player 2".to_string()),
+                ..Default::default()
+            },
+        );
+        let previous = vec![controller("/dev/hidraw3", 90, Status::Charging)];
+        let current = vec![
+            controller("/dev/hidraw1", 15, Status::Discharging),
+            controller("/dev/hidraw2", 5, Status::Discharging),
+            controller("/dev/hidraw3", 100, Status::Charging),
+        ];
+        let mut alerts = AlertState::default();
+        let messages = battery_alerts(None, &current, &HashMap::new(), &settings, &mut alerts);
+        assert_eq!(messages.len(), 2);
+        assert!(
+            matches!(&messages[1], ServerMessage::LowBattery(alert) if alert.message == "Player 2 is critically low on battery (5%)")
+        );
+
+        let messages = group_alerts(messages, &settings);
+        assert_eq!(messages.len(), 1);
+        assert!(
+            matches!(&messages[0], ServerMessage::AlertGroup(group) if group.message == "2 controllers low: DualSense 15%, Player 2 5%" && group.critical)
+        );
+        // Still there one by one for webhooks and D-Bus
+        let ungrouped = messages[0].ungroup();
+        assert_eq!(ungrouped.len(), 2);
+        assert!(matches!(&ungrouped[1], ServerMessage::LowBattery(alert) if alert.capacity == 5));
+
+        // Anything else is listed as is
+        let mut alerts = AlertState::default();
+        let messages = battery_alerts(
+            Some(&previous),
+            &current,
+            &HashMap::new(),
+            &settings,
+            &mut alerts,
+        );
+        let messages = group_alerts(messages, &settings);
+        assert!(
+            matches!(&messages[0], ServerMessage::AlertGroup(group) if group.alerts.len() == 3 && group.message.ends_with("; DualSense is fully charged, unplug it"))
+        );
+
+        // A single alert stays as it is
+        let messages = battery_alerts(
+            None,
+            &current[..1],
+            &HashMap::new(),
+            &settings,
+            &mut AlertState::default(),
+        );
+        assert!(matches!(
+            &group_alerts(messages, &settings)[0],
+            ServerMessage::LowBattery(_)
+        ));
+
+        // Templates
+        settings.templates.low_battery = Some("Charge {nickname} ({capacity}%)".to_string());
+        let messages = battery_alerts(
+            None,
+            &current[..1],
+            &HashMap::new(),
+            &settings,
+            &mut AlertState::default(),
+        );
+        assert!(
+            matches!(&messages[0], ServerMessage::LowBattery(alert) if alert.message == "Charge DualSense (15%)")
+        );
+    }
+}
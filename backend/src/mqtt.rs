@@ -0,0 +1,596 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    controller::Controller,
+    settings::{MqttSettings, Settings},
+    AppState,
+};
+
+// Requests queued for the event loop, a full announce is 5 messages per controller
+const CHANNEL_CAPACITY: usize = 64;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// How long to wait before connecting again after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Publishes the controllers found by the monitor to the MQTT broker in the settings, if any.
+/// Every message is retained, so subscribers get the latest state as soon as they subscribe.
+///
+/// Topics, under the `topicPrefix`:
+/// - `status`: `online` while the backend is connected, `offline` otherwise (last will)
+/// - `<stable id>/state`: JSON with the controller's `battery`, `status`, `transport` and `name`
+/// - `<stable id>/connection`: `online` while the controller is connected, `offline` once gone
+///
+/// Home Assistant discovers each controller as a device with a battery, a charging and a
+/// connectivity sensor.
+pub async fn run(state: Arc<AppState>) -> Result<()> {
+    let mut settings = state.settings_service.subscribe();
+    loop {
+        let mqtt = settings.borrow_and_update().mqtt.clone();
+        let Some(broker) = &mqtt else {
+            mqtt_changed(&mut settings, &mqtt).await?;
+            continue;
+        };
+
+        info!("Publishing to MQTT broker {}:{}", broker.host, broker.port);
+        let session = session(
+            broker,
+            state.settings_service.subscribe(),
+            state.controllers.subscribe(),
+        );
+        tokio::select! {
+            result = session => result?,
+            result = mqtt_changed(&mut settings, &mqtt) => result?,
+        }
+        // Dropping the session closes the connection, the broker publishes our last will
+        debug!("MQTT settings changed");
+    }
+}
+
+/// Waits for the MQTT settings to differ from `current`
+async fn mqtt_changed(
+    settings: &mut watch::Receiver<Settings>,
+    current: &Option<MqttSettings>,
+) -> Result<()> {
+    loop {
+        settings.changed().await?;
+        if settings.borrow_and_update().mqtt != *current {
+            return Ok(());
+        }
+    }
+}
+
+/// Stays connected to the broker, reconnecting whenever the connection is lost, and publishes
+/// the controllers as they change
+async fn session(
+    broker: &MqttSettings,
+    settings: watch::Receiver<Settings>,
+    mut controllers: watch::Receiver<Vec<Controller>>,
+) -> Result<()> {
+    let (client, eventloop) = AsyncClient::new(options(broker), CHANNEL_CAPACITY);
+    // Connections to the broker and Home Assistant restarts, both want everything again
+    let (announce_sender, mut announce) = mpsc::unbounded_channel();
+    let birth_topic = format!("{}/status", broker.discovery_prefix);
+    let events = poll(eventloop, client.clone(), birth_topic, announce_sender);
+
+    let mut publisher = Publisher {
+        client,
+        broker,
+        published: BTreeMap::new(),
+    };
+    let publish = async {
+        loop {
+            tokio::select! {
+                Some(()) = announce.recv() => {
+                    let current = controllers.borrow_and_update().clone();
+                    let settings = settings.borrow().clone();
+                    publisher.announce(&current, &settings).await?;
+                }
+                Ok(()) = controllers.changed() => {
+                    let current = controllers.borrow_and_update().clone();
+                    let settings = settings.borrow().clone();
+                    publisher.update(&current, &settings).await?;
+                }
+            }
+        }
+    };
+    tokio::select! {
+        () = events => Ok(()),
+        result = publish => result,
+    }
+}
+
+/// Drives the connection to the broker, which only makes progress while this runs
+async fn poll(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    birth_topic: String,
+    announce: mpsc::UnboundedSender<()>,
+) {
+    let mut connected = true;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                connected = true;
+                if let Err(err) = client.try_subscribe(&birth_topic, QoS::AtLeastOnce) {
+                    warn!("Failed to subscribe to {}: {}", birth_topic, err);
+                }
+                let _ = announce.send(());
+            }
+            Ok(Event::Incoming(Packet::Publish(publish)))
+                if publish.topic == birth_topic && publish.payload == ONLINE =>
+            {
+                debug!("Home Assistant came online");
+                let _ = announce.send(());
+            }
+            Ok(_) => {}
+            Err(err) => {
+                // Only warn once, the broker may well stay down for a while
+                if connected {
+                    warn!("MQTT connection failed: {}", err);
+                    connected = false;
+                } else {
+                    debug!("MQTT connection failed: {}", err);
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+fn options(broker: &MqttSettings) -> MqttOptions {
+    let mut options = MqttOptions::new(&broker.client_id, &broker.host, broker.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        status_topic(broker),
+        OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &broker.username {
+        options.set_credentials(username, broker.password.as_deref().unwrap_or_default());
+    }
+    options
+}
+
+struct Publisher<'a> {
+    client: AsyncClient,
+    broker: &'a MqttSettings,
+    // Last state published for each connected controller, by stable id
+    published: BTreeMap<String, Value>,
+}
+
+impl Publisher<'_> {
+    /// Publishes everything again, e.g. to a broker that lost its retained messages
+    async fn announce(&mut self, controllers: &[Controller], settings: &Settings) -> Result<()> {
+        self.publish(status_topic(self.broker), ONLINE).await?;
+        self.published.clear();
+        self.update(controllers, settings).await
+    }
+
+    /// Publishes what changed since the last update
+    async fn update(&mut self, controllers: &[Controller], settings: &Settings) -> Result<()> {
+        let gone: Vec<String> = self
+            .published
+            .keys()
+            .filter(|id| !controllers.iter().any(|c| c.stable_id() == **id))
+            .cloned()
+            .collect();
+        for id in gone {
+            self.published.remove(&id);
+            let topic = controller_topic(self.broker, &id, "connection");
+            self.publish(topic, OFFLINE).await?;
+        }
+
+        for controller in controllers {
+            let id = controller.stable_id();
+            let state = state(controller);
+            match self.published.get(&id) {
+                Some(published) if *published == state => continue,
+                Some(_) => {}
+                None => {
+                    let name = settings.nickname(&id).unwrap_or(&controller.name);
+                    for (topic, config) in discovery(self.broker, controller, name) {
+                        self.publish(topic, config.to_string()).await?;
+                    }
+                    let topic = controller_topic(self.broker, &id, "connection");
+                    self.publish(topic, ONLINE).await?;
+                }
+            }
+            let topic = controller_topic(self.broker, &id, "state");
+            self.publish(topic, state.to_string()).await?;
+            self.published.insert(id, state);
+        }
+        Ok(())
+    }
+
+    async fn publish(&self, topic: String, payload: impl Into<Vec<u8>>) -> Result<()> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .await?;
+        Ok(())
+    }
+}
+
+fn status_topic(broker: &MqttSettings) -> String {
+    format!("{}/status", broker.topic_prefix)
+}
+
+fn controller_topic(broker: &MqttSettings, stable_id: &str, topic: &str) -> String {
+    format!("{}/{}/{}", broker.topic_prefix, stable_id, topic)
+}
+
+fn state(controller: &Controller) -> Value {
+    json!({
+        "battery": controller.capacity,
+        "status": controller.status,
+        "transport": if controller.bluetooth { "bluetooth" } else { "usb" },
+        "name": controller.name,
+    })
+}
+
+/// Home Assistant MQTT discovery configs of a controller's sensors, by config topic
+fn discovery(broker: &MqttSettings, controller: &Controller, name: &str) -> Vec<(String, Value)> {
+    let id = controller.stable_id();
+    let state_topic = controller_topic(broker, &id, "state");
+    let connection_topic = controller_topic(broker, &id, "connection");
+    let device = json!({
+        "identifiers": [format!("controller-tools-{}", id)],
+        "name": name,
+        "model": controller.name,
+        "manufacturer": manufacturer(controller.vendor_id),
+    });
+    // The battery is only known while both the backend and the controller are connected
+    let availability = json!([
+        { "topic": status_topic(broker) },
+        { "topic": connection_topic },
+    ]);
+    let config_topic = |component: &str, object: &str| {
+        format!(
+            "{}/{}/{}/{}/config",
+            broker.discovery_prefix, component, id, object
+        )
+    };
+
+    vec![
+        (
+            config_topic("sensor", "battery"),
+            json!({
+                "name": "Battery",
+                "unique_id": format!("{}-battery", id),
+                "device_class": "battery",
+                "state_class": "measurement",
+                "unit_of_measurement": "%",
+                "state_topic": state_topic,
+                "value_template": "{{ value_json.battery }}",
+                "json_attributes_topic": state_topic,
+                "availability": availability,
+                "availability_mode": "all",
+                "device": device,
+            }),
+        ),
+        (
+            config_topic("binary_sensor", "charging"),
+            json!({
+                "name": "Charging",
+                "unique_id": format!("{}-charging", id),
+                "device_class": "battery_charging",
+                "state_topic": state_topic,
+                "value_template": "{{ 'ON' if value_json.status == 'charging' else 'OFF' }}",
+                "availability": availability,
+                "availability_mode": "all",
+                "device": device,
+            }),
+        ),
+        (
+            config_topic("binary_sensor", "connected"),
+            json!({
+                "name": "Connected",
+                "unique_id": format!("{}-connected", id),
+                "device_class": "connectivity",
+                "state_topic": connection_topic,
+                "payload_on": ONLINE,
+                "payload_off": OFFLINE,
+                "availability_topic": status_topic(broker),
+                "device": device,
+            }),
+        ),
+    ]
+}
+
+fn manufacturer(vendor_id: u16) -> Option<&'static str> {
+    match vendor_id {
+        0x054c => Some("Sony"),
+        0x057e => Some("Nintendo"),
+        0x045e => Some("Microsoft"),
+        0x28de => Some("Valve"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use anyhow::{bail, Context};
+    use bytes::BytesMut;
+    use rumqttc::{
+        ConnAck, ConnectReturnCode, Packet, PubAck, Publish, QoS, SubAck, SubscribeReasonCode,
+    };
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, watch},
+    };
+
+    use super::{discovery, session};
+    use crate::{
+        controller::{Controller, Status},
+        settings::{MqttSettings, Settings},
+    };
+
+    const ID: &str = "054c-0ce6-a0-ab-51-12-34-56";
+
+    fn controller(capacity: u8) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity,
+            status: Status::Discharging,
+            bluetooth: true,
+            serial_number: Some("a0:ab:51:12:34:56".to_string()),
+            device_path: Some("/dev/hidraw1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_discovery() {
+        let configs = discovery(&MqttSettings::default(), &controller(40), "Player 1");
+        let topics: Vec<_> = configs.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                format!("homeassistant/sensor/{ID}/battery/config"),
+                format!("homeassistant/binary_sensor/{ID}/charging/config"),
+                format!("homeassistant/binary_sensor/{ID}/connected/config"),
+            ]
+        );
+        let battery = &configs[0].1;
+        assert_eq!(battery["device_class"], "battery");
+        assert_eq!(
+            battery["state_topic"],
+            format!("controller-tools/{ID}/state")
+        );
+        assert_eq!(battery["device"]["name"], "Player 1");
+        assert_eq!(battery["device"]["manufacturer"], "Sony");
+        assert_eq!(
+            configs[2].1["state_topic"],
+            format!("controller-tools/{ID}/connection")
+        );
+    }
+
+    /// Plays the broker for a single client: acknowledges what it sends and passes it on
+    async fn broker(
+        mut stream: TcpStream,
+        received: mpsc::UnboundedSender<Packet>,
+        mut outgoing: mpsc::UnboundedReceiver<Packet>,
+    ) -> anyhow::Result<()> {
+        let mut input = BytesMut::new();
+        loop {
+            let packet = match Packet::read(&mut input, 1 << 20) {
+                Ok(packet) => packet,
+                Err(rumqttc::Error::InsufficientBytes(_)) => {
+                    tokio::select! {
+                        read = stream.read_buf(&mut input) => {
+                            if read? == 0 {
+                                return Ok(());
+                            }
+                        }
+                        Some(packet) = outgoing.recv() => send(&mut stream, packet).await?,
+                    }
+                    continue;
+                }
+                Err(err) => bail!("Invalid packet: {:?}", err),
+            };
+            let reply = match &packet {
+                Packet::Connect(_) => Some(Packet::ConnAck(ConnAck::new(
+                    ConnectReturnCode::Success,
+                    false,
+                ))),
+                Packet::Publish(publish) if publish.qos == QoS::AtLeastOnce => {
+                    Some(Packet::PubAck(PubAck::new(publish.pkid)))
+                }
+                Packet::Subscribe(subscribe) => Some(Packet::SubAck(SubAck::new(
+                    subscribe.pkid,
+                    vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)],
+                ))),
+                Packet::PingReq => Some(Packet::PingResp),
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                send(&mut stream, reply).await?;
+            }
+            received.send(packet)?;
+        }
+    }
+
+    async fn send(stream: &mut TcpStream, packet: Packet) -> anyhow::Result<()> {
+        let mut output = BytesMut::new();
+        packet.write(&mut output, 1 << 20)?;
+        stream.write_all(&output).await?;
+        Ok(())
+    }
+
+    /// The next message published by the client, as topic and payload
+    async fn next_publish(
+        received: &mut mpsc::UnboundedReceiver<Packet>,
+    ) -> anyhow::Result<(String, String)> {
+        loop {
+            let packet = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await?
+                .context("Client disconnected")?;
+            if let Packet::Publish(publish) = packet {
+                assert!(publish.retain, "{} not retained", publish.topic);
+                let payload = String::from_utf8(publish.payload.to_vec())?;
+                return Ok((publish.topic, payload));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let broker_settings = MqttSettings {
+            port: listener.local_addr()?.port(),
+            ..Default::default()
+        };
+        let (_settings, settings_receiver) = watch::channel(Settings::default());
+        let (controllers, controllers_receiver) = watch::channel(vec![controller(40)]);
+        let client = tokio::spawn(async move {
+            session(&broker_settings, settings_receiver, controllers_receiver).await
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let (received_sender, mut received) = mpsc::unbounded_channel();
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(broker(stream, received_sender, outgoing_receiver));
+
+        let Some(Packet::Connect(connect)) = received.recv().await else {
+            bail!("Expected a connect packet");
+        };
+        let will = connect.last_will.context("No last will")?;
+        assert_eq!(will.topic, "controller-tools/status");
+        assert_eq!(will.message, "offline");
+        assert!(will.retain);
+
+        // Everything is published once connected
+        let mut topics = Vec::new();
+        let state = loop {
+            let (topic, payload) = next_publish(&mut received).await?;
+            if topic.ends_with("/state") {
+                break serde_json::from_str::<Value>(&payload)?;
+            }
+            topics.push(topic);
+        };
+        assert_eq!(
+            topics,
+            [
+                "controller-tools/status".to_string(),
+                format!("homeassistant/sensor/{ID}/battery/config"),
+                format!("homeassistant/binary_sensor/{ID}/charging/config"),
+                format!("homeassistant/binary_sensor/{ID}/connected/config"),
+                format!("controller-tools/{ID}/connection"),
+            ]
+        );
+        assert_eq!(state["battery"], 40);
+        assert_eq!(state["status"], "discharging");
+        assert_eq!(state["transport"], "bluetooth");
+
+        // Then only what changes
+        controllers.send_replace(vec![controller(30)]);
+        let (topic, payload) = next_publish(&mut received).await?;
+        assert_eq!(topic, format!("controller-tools/{ID}/state"));
+        assert_eq!(serde_json::from_str::<Value>(&payload)?["battery"], 30);
+
+        controllers.send_replace(Vec::new());
+        assert_eq!(
+            next_publish(&mut received).await?,
+            (
+                format!("controller-tools/{ID}/connection"),
+                "offline".to_string()
+            )
+        );
+
+        // Home Assistant coming online wants everything again
+        outgoing.send(Packet::Publish(Publish::new(
+            "homeassistant/status",
+            QoS::AtMostOnce,
+            "online",
+        )))?;
+        assert_eq!(
+            next_publish(&mut received).await?,
+            ("controller-tools/status".to_string(), "online".to_string())
+        );
+
+        client.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_without_serial_numbers() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let broker_settings = MqttSettings {
+            port: listener.local_addr()?.port(),
+            ..Default::default()
+        };
+        // Two identical controllers that don't report a serial number
+        let pad = |capacity, device_path: &str| Controller {
+            serial_number: None,
+            device_path: Some(device_path.to_string()),
+            ..controller(capacity)
+        };
+        let (_settings, settings_receiver) = watch::channel(Settings::default());
+        let (controllers, controllers_receiver) =
+            watch::channel(vec![pad(40, "/dev/hidraw1"), pad(80, "/dev/hidraw2")]);
+        let client = tokio::spawn(async move {
+            session(&broker_settings, settings_receiver, controllers_receiver).await
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let (received_sender, mut received) = mpsc::unbounded_channel();
+        let (_outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(broker(stream, received_sender, outgoing_receiver));
+
+        // Each gets its own state instead of overwriting the other's
+        let mut states = BTreeMap::new();
+        while states.len() < 2 {
+            let (topic, payload) = next_publish(&mut received).await?;
+            if topic.ends_with("/state") {
+                states.insert(
+                    topic,
+                    serde_json::from_str::<Value>(&payload)?["battery"].clone(),
+                );
+            }
+        }
+        assert_eq!(
+            states,
+            BTreeMap::from([
+                (
+                    "controller-tools/054c-0ce6-hidraw1/state".to_string(),
+                    40.into()
+                ),
+                (
+                    "controller-tools/054c-0ce6-hidraw2/state".to_string(),
+                    80.into()
+                ),
+            ])
+        );
+
+        controllers.send_replace(vec![pad(30, "/dev/hidraw1"), pad(80, "/dev/hidraw2")]);
+        let (topic, payload) = next_publish(&mut received).await?;
+        assert_eq!(topic, "controller-tools/054c-0ce6-hidraw1/state");
+        assert_eq!(serde_json::from_str::<Value>(&payload)?["battery"], 30);
+
+        controllers.send_replace(vec![pad(80, "/dev/hidraw2")]);
+        assert_eq!(
+            next_publish(&mut received).await?,
+            (
+                "controller-tools/054c-0ce6-hidraw1/connection".to_string(),
+                "offline".to_string()
+            )
+        );
+
+        client.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,542 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{controller::Controller, estimate::Estimate};
+
+/// Version of the JSON protocol spoken over `/ws`. Bump it whenever a message changes in a way
+/// that older clients can't ignore.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Every message on the socket is wrapped in an envelope:
+/// `{"type": "low_battery", "version": 1, "payload": {...}}`
+///
+/// Commands sent by a client may carry an `id`, which is copied into the envelope of the reply.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(message: T) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id: None,
+            message,
+        }
+    }
+}
+
+/// Messages sent from the backend to a client
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello(ServerHello),
+    Snapshot(Snapshot),
+    ControllerUpdated(ControllerState),
+    Connected(ControllerState),
+    Disconnected(ControllerRef),
+    LowBattery(BatteryAlert),
+    Charged(BatteryAlert),
+    ChargingStarted(BatteryAlert),
+    /// A controller disconnected while low on battery
+    LostWhileLow(BatteryAlert),
+    /// Notification counterparts of `Connected` and `Disconnected`, which keep the client's view
+    /// of the controllers up to date and are always sent
+    ConnectedAlert(BatteryAlert),
+    DisconnectedAlert(BatteryAlert),
+    /// The alerts held back during quiet time, sent once it's over
+    Digest(Digest),
+    /// Several alerts raised by the same check, in a single notification
+    AlertGroup(AlertGroup),
+    Error(ErrorPayload),
+    /// Successful reply to a client command
+    Response(CommandResult),
+}
+
+/// Messages sent from a client to the backend
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Hello(ClientHello),
+    /// Check the controllers right away instead of waiting for the monitor's next check
+    Refresh,
+    Subscribe(SubscriptionChange),
+    Unsubscribe(SubscriptionChange),
+    /// Stop repeating the low battery alert of a controller until it's charged again
+    AckAlert(AlertTarget),
+    /// Silence all alerts of a controller for a while
+    SnoozeAlert(SnoozeAlert),
+    /// Hold back every alert for a while, 0 minutes ends the snooze
+    SnoozeAll(SnoozeAll),
+    /// Send controller changes at most this often
+    SetPollInterval(PollInterval),
+}
+
+/// The kinds of events a client can subscribe to
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    ControllerUpdated,
+    Connected,
+    Disconnected,
+    LowBattery,
+    Charged,
+    ChargingStarted,
+    LostWhileLow,
+    ConnectedAlert,
+    DisconnectedAlert,
+    Digest,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 10] = [
+        EventKind::ControllerUpdated,
+        EventKind::Connected,
+        EventKind::Disconnected,
+        EventKind::LowBattery,
+        EventKind::Charged,
+        EventKind::ChargingStarted,
+        EventKind::LostWhileLow,
+        EventKind::ConnectedAlert,
+        EventKind::DisconnectedAlert,
+        EventKind::Digest,
+    ];
+}
+
+/// Fields left out of a subscribe/unsubscribe command are left untouched
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionChange {
+    #[serde(default)]
+    pub controllers: Option<Vec<String>>,
+    #[serde(default)]
+    pub events: Option<Vec<EventKind>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertTarget {
+    pub controller: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnoozeAlert {
+    pub controller: String,
+    pub minutes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnoozeAll {
+    pub minutes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollInterval {
+    pub seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum CommandResult {
+    Snapshot(Snapshot),
+    Subscriptions(Subscriptions),
+    PollInterval(PollInterval),
+    Done {},
+}
+
+/// What a session currently receives. `controllers` is `None` when subscribed to every
+/// controller, `excluded` lists the ones unsubscribed from in that case.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscriptions {
+    pub controllers: Option<BTreeSet<String>>,
+    pub excluded: BTreeSet<String>,
+    pub events: BTreeSet<EventKind>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self {
+            controllers: None,
+            excluded: BTreeSet::new(),
+            events: EventKind::ALL.into_iter().collect(),
+        }
+    }
+}
+
+impl Subscriptions {
+    /// The first subscription to specific controllers narrows the session down to them
+    pub fn subscribe(&mut self, change: SubscriptionChange) {
+        if let Some(ids) = change.controllers {
+            for id in &ids {
+                self.excluded.remove(id);
+            }
+            self.controllers
+                .get_or_insert_with(BTreeSet::new)
+                .extend(ids);
+        }
+        if let Some(events) = change.events {
+            self.events.extend(events);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, change: SubscriptionChange) {
+        if let Some(ids) = change.controllers {
+            match &mut self.controllers {
+                Some(controllers) => {
+                    for id in &ids {
+                        controllers.remove(id);
+                    }
+                }
+                None => self.excluded.extend(ids),
+            }
+        }
+        if let Some(events) = change.events {
+            for event in &events {
+                self.events.remove(event);
+            }
+        }
+    }
+
+    pub fn wants_controller(&self, id: &str) -> bool {
+        match &self.controllers {
+            Some(controllers) => controllers.contains(id),
+            None => !self.excluded.contains(id),
+        }
+    }
+
+    /// Whether a message should be delivered to this session. Messages that aren't events,
+    /// like errors and replies, are always delivered.
+    pub fn wants(&self, message: &ServerMessage) -> bool {
+        let Some(kind) = message.event_kind() else {
+            return true;
+        };
+        let id = match message {
+            ServerMessage::ControllerUpdated(state) | ServerMessage::Connected(state) => &state.id,
+            ServerMessage::Disconnected(controller) => &controller.id,
+            // Not about a single controller
+            ServerMessage::Digest(_) => return self.events.contains(&kind),
+            message => match message.alert() {
+                Some(alert) => &alert.id,
+                None => return true,
+            },
+        };
+        self.events.contains(&kind) && self.wants_controller(id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerHello {
+    pub server: String,
+    pub server_version: String,
+    pub protocol_version: u32,
+}
+
+impl Default for ServerHello {
+    fn default() -> Self {
+        Self {
+            server: env!("CARGO_PKG_NAME").to_string(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientHello {
+    #[serde(default)]
+    pub client: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub controllers: Vec<ControllerState>,
+}
+
+/// A controller as seen by clients. The `id` lets clients match updates to earlier messages,
+/// the `stableId` survives reconnects and is the one used by the history.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerState {
+    pub id: String,
+    pub stable_id: String,
+    #[serde(flatten)]
+    pub controller: Controller,
+    /// Time left, once there is enough history to tell
+    #[serde(flatten)]
+    pub estimate: Option<Estimate>,
+}
+
+impl From<&Controller> for ControllerState {
+    fn from(controller: &Controller) -> Self {
+        Self {
+            id: controller.id(),
+            stable_id: controller.stable_id(),
+            controller: controller.clone(),
+            estimate: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerRef {
+    pub id: String,
+    pub name: String,
+}
+
+impl From<&Controller> for ControllerRef {
+    fn from(controller: &Controller) -> Self {
+        Self {
+            id: controller.id(),
+            name: controller.name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatteryAlert {
+    pub id: String,
+    pub stable_id: String,
+    pub vendor_id: u16,
+    pub name: String,
+    pub capacity: u8,
+    /// Human readable text, ready to be shown in a toast
+    pub message: String,
+    /// The controller is below its critical threshold
+    pub critical: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertGroup {
+    /// Human readable text, ready to be shown in a toast
+    pub message: String,
+    /// One of the alerts is critical
+    pub critical: bool,
+    pub alerts: Vec<BatteryAlert>,
+    /// The grouped alerts as they were raised, for the subscribers that take them one by one
+    #[serde(skip)]
+    pub messages: Vec<ServerMessage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Digest {
+    /// Human readable text, ready to be shown in a toast
+    pub message: String,
+    pub alerts: Vec<String>,
+}
+
+impl BatteryAlert {
+    pub fn new(controller: &Controller, message: String) -> Self {
+        Self {
+            id: controller.id(),
+            stable_id: controller.stable_id(),
+            vendor_id: controller.vendor_id,
+            name: controller.name.clone(),
+            capacity: controller.capacity,
+            message,
+            critical: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    VersionMismatch,
+    HandshakeFailed,
+    ProbeFailed,
+    InvalidMessage,
+    InvalidCommand,
+    UnknownController,
+}
+
+impl ErrorPayload {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error(ErrorPayload::new(code, message))
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_reply_json(None)
+    }
+
+    /// The kind of event the message is, `None` for the ones that always get sent like replies
+    pub fn event_kind(&self) -> Option<EventKind> {
+        match self {
+            ServerMessage::ControllerUpdated(_) => Some(EventKind::ControllerUpdated),
+            ServerMessage::Connected(_) => Some(EventKind::Connected),
+            ServerMessage::Disconnected(_) => Some(EventKind::Disconnected),
+            ServerMessage::LowBattery(_) => Some(EventKind::LowBattery),
+            ServerMessage::Charged(_) => Some(EventKind::Charged),
+            ServerMessage::ChargingStarted(_) => Some(EventKind::ChargingStarted),
+            ServerMessage::LostWhileLow(_) => Some(EventKind::LostWhileLow),
+            ServerMessage::ConnectedAlert(_) => Some(EventKind::ConnectedAlert),
+            ServerMessage::DisconnectedAlert(_) => Some(EventKind::DisconnectedAlert),
+            ServerMessage::Digest(_) => Some(EventKind::Digest),
+            _ => None,
+        }
+    }
+
+    /// The notification carried by the message, if it's one
+    pub fn alert(&self) -> Option<&BatteryAlert> {
+        match self {
+            ServerMessage::LowBattery(alert)
+            | ServerMessage::Charged(alert)
+            | ServerMessage::ChargingStarted(alert)
+            | ServerMessage::LostWhileLow(alert)
+            | ServerMessage::ConnectedAlert(alert)
+            | ServerMessage::DisconnectedAlert(alert) => Some(alert),
+            _ => None,
+        }
+    }
+
+    /// The alerts of a group one by one, any other message as it is
+    pub fn ungroup(&self) -> &[ServerMessage] {
+        match self {
+            ServerMessage::AlertGroup(group) => &group.messages,
+            message => std::slice::from_ref(message),
+        }
+    }
+
+    /// Serializes the message as the reply to the client command with the given id
+    pub fn to_reply_json(&self, id: Option<&str>) -> String {
+        let mut envelope = Envelope::new(self);
+        envelope.id = id.map(str::to_string);
+        // Serializing our own enums into a `String` can't fail
+        serde_json::to_string(&envelope).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ClientMessage, CommandResult, Envelope, ErrorCode, EventKind, ServerMessage,
+        SubscriptionChange, Subscriptions, PROTOCOL_VERSION,
+    };
+    use crate::controller::{Controller, Status};
+
+    #[test]
+    fn test_server_message_envelope() {
+        let controller = Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity: 15,
+            status: Status::Discharging,
+            bluetooth: true,
+            serial_number: None,
+            device_path: Some("/dev/hidraw3".to_string()),
+        };
+        let message = ServerMessage::Connected((&controller).into());
+        assert_eq!(
+            message.to_json(),
+            r#"{"version":1,"type":"connected","payload":{"id":"/dev/hidraw3","stableId":"054c-0ce6-hidraw3","name":"DualSense","productId":3302,"vendorId":1356,"capacity":15,"status":"discharging","bluetooth":true}}"#
+        );
+
+        let message = ServerMessage::error(ErrorCode::VersionMismatch, "nope");
+        assert_eq!(
+            message.to_json(),
+            r#"{"version":1,"type":"error","payload":{"code":"version_mismatch","message":"nope"}}"#
+        );
+    }
+
+    #[test]
+    fn test_client_hello() {
+        let envelope: Envelope<ClientMessage> =
+            serde_json::from_str(r#"{"type":"hello","version":1,"payload":{"client":"decky"}}"#)
+                .unwrap();
+        assert_eq!(envelope.version, PROTOCOL_VERSION);
+        assert!(
+            matches!(envelope.message, ClientMessage::Hello(hello) if hello.client.as_deref() == Some("decky"))
+        );
+
+        // Unknown message types are rejected
+        let envelope = serde_json::from_str::<Envelope<ClientMessage>>(
+            r#"{"type":"launch_missiles","version":1,"payload":{}}"#,
+        );
+        assert!(envelope.is_err());
+    }
+
+    #[test]
+    fn test_client_command() {
+        let envelope: Envelope<ClientMessage> = serde_json::from_str(
+            r#"{"type":"set_poll_interval","version":1,"id":"42","payload":{"seconds":5}}"#,
+        )
+        .unwrap();
+        assert_eq!(envelope.id.as_deref(), Some("42"));
+        assert!(matches!(
+            envelope.message,
+            ClientMessage::SetPollInterval(interval) if interval.seconds == 5
+        ));
+
+        // Commands without arguments don't need a payload
+        let envelope: Envelope<ClientMessage> =
+            serde_json::from_str(r#"{"type":"refresh","version":1}"#).unwrap();
+        assert!(matches!(envelope.message, ClientMessage::Refresh));
+
+        let reply = ServerMessage::Response(CommandResult::Done {});
+        assert_eq!(
+            reply.to_reply_json(Some("42")),
+            r#"{"version":1,"id":"42","type":"response","payload":{}}"#
+        );
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.wants_controller("a"));
+
+        // Unsubscribing while subscribed to everything excludes the controller
+        subscriptions.unsubscribe(SubscriptionChange {
+            controllers: Some(vec!["a".to_string()]),
+            events: Some(vec![EventKind::Connected]),
+        });
+        assert!(!subscriptions.wants_controller("a"));
+        assert!(subscriptions.wants_controller("b"));
+        assert!(!subscriptions.events.contains(&EventKind::Connected));
+
+        // Subscribing to specific controllers narrows the session down to them
+        subscriptions.subscribe(SubscriptionChange {
+            controllers: Some(vec!["a".to_string()]),
+            events: None,
+        });
+        assert!(subscriptions.wants_controller("a"));
+        assert!(!subscriptions.wants_controller("b"));
+
+        subscriptions.unsubscribe(SubscriptionChange {
+            controllers: Some(vec!["a".to_string()]),
+            events: None,
+        });
+        assert!(!subscriptions.wants_controller("a"));
+    }
+}
@@ -1,13 +1,245 @@
-use anyhow::Result;
-use log::error;
-use serde::Deserialize;
-use std::sync::Mutex;
-use tokio::fs::File;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Weekday};
+use futures::StreamExt;
+use inotify::{Inotify, WatchMask};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::watch, sync::Mutex};
 
-#[derive(Deserialize, Clone)]
+use crate::protocol::EventKind;
+
+/// Version of the config file schema. Bump it and add a migration to `MIGRATIONS` whenever an
+/// existing option changes meaning or shape. New options only need a default.
+pub const SETTINGS_VERSION: u32 = 1;
+
+/// Sent instead of the webhook secrets and the MQTT password when the settings leave the backend.
+/// Saving it back keeps the stored value.
+pub const REDACTED: &str = "********";
+
+/// `MIGRATIONS[n]` upgrades a config file from version `n` to version `n + 1`
+const MIGRATIONS: [fn(&mut Map<String, Value>); SETTINGS_VERSION as usize] = [migrate_v0_to_v1];
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
 pub struct Settings {
+    pub version: u32,
     pub notifications: bool,
+    /// Also show the alerts through the desktop's notification server, for when the backend
+    /// runs without the Decky frontend
+    pub desktop_notifications: bool,
     pub debug: bool,
+    pub alerts: AlertSettings,
+    pub events: NotificationEvents,
+    pub quiet: QuietSettings,
+    pub templates: MessageTemplates,
+    /// Per controller settings, keyed by `Controller::stable_id`
+    pub controllers: BTreeMap<String, ControllerSettings>,
+    pub webhooks: Vec<WebhookSettings>,
+    /// Publishes the controllers to an MQTT broker when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttSettings>,
+    /// Keys we don't know about, e.g. written by a newer version. Kept so saving doesn't drop them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// When to warn about a low battery. Capacities are in percent, a controller is low once its
+/// capacity drops below the threshold while discharging.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AlertSettings {
+    pub warning_threshold: u8,
+    pub critical_threshold: u8,
+    /// How often the warning is repeated while the controller stays low
+    pub warning_repeat_minutes: u32,
+    /// How often the alert is repeated once the controller is critically low
+    pub critical_repeat_minutes: u32,
+}
+
+/// Which kinds of notifications are sent, when notifications are enabled
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NotificationEvents {
+    pub low_battery: bool,
+    pub charged: bool,
+    pub charging_started: bool,
+    pub connected: bool,
+    pub disconnected: bool,
+    /// A controller disconnected while low on battery, it most likely ran flat
+    pub lost_while_low: bool,
+}
+
+impl Default for NotificationEvents {
+    fn default() -> Self {
+        Self {
+            low_battery: true,
+            charged: true,
+            charging_started: false,
+            connected: false,
+            disconnected: false,
+            lost_while_low: true,
+        }
+    }
+}
+
+/// When alerts are held back, e.g. at night or while streaming
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct QuietSettings {
+    pub schedule: Vec<QuietPeriod>,
+    /// What happens to the alerts held back during quiet time
+    pub suppressed: SuppressedAlerts,
+}
+
+/// A quiet period in local time, e.g. from 22:00 to 07:00 on weekdays. A period that ends before
+/// it starts runs past midnight and belongs to the day it starts.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietPeriod {
+    /// Every day when empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SuppressedAlerts {
+    Drop,
+    /// Sent together in a single digest once the quiet time is over
+    #[default]
+    Digest,
+}
+
+impl QuietSettings {
+    /// Whether `now` falls in one of the quiet periods
+    pub fn is_quiet<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        let today = now.weekday();
+        let yesterday = (now.clone() - Duration::days(1)).weekday();
+        let time = now.time();
+        self.schedule.iter().any(|period| {
+            let on = |day: Weekday| period.days.is_empty() || period.days.contains(&day);
+            if period.start <= period.end {
+                on(today) && period.start <= time && time < period.end
+            } else {
+                (on(today) && period.start <= time) || (on(yesterday) && time < period.end)
+            }
+        })
+    }
+}
+
+/// An HTTP endpoint the alerts are posted to, e.g. a home automation server
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookSettings {
+    pub url: String,
+    /// Kinds of alerts posted, every kind when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Key of the HMAC-SHA256 signature of the body, sent in the `X-Signature-256` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+/// The MQTT broker the controllers are published to. Topics are made of the `topicPrefix` and
+/// the controller's stable id, Home Assistant discovers them under `discoveryPrefix`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    /// Has to be unique on the broker, e.g. when several devices publish to it
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub topic_prefix: String,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "controller-tools".to_string(),
+            username: None,
+            password: None,
+            topic_prefix: "controller-tools".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+/// Overrides of the global settings for a single controller
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ControllerSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning_threshold: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub critical_threshold: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning_repeat_minutes: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub critical_repeat_minutes: Option<u32>,
+    /// Shown instead of the controller's name in alerts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    #[serde(skip_serializing_if = "MessageTemplates::is_empty")]
+    pub templates: MessageTemplates,
+}
+
+/// Custom texts for the alerts, the built-in text is used for those left out. Templates can use
+/// the `{name}`, `{nickname}`, `{capacity}` and `{remaining}` placeholders, see
+/// `alerts::render`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MessageTemplates {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_battery: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub critical_battery: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charged: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charging_started: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disconnected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lost_while_low: Option<String>,
+}
+
+impl MessageTemplates {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// `self` with the templates it leaves out taken from `fallback`
+    fn or(&self, fallback: &Self) -> Self {
+        let pick = |template: &Option<String>, fallback: &Option<String>| {
+            template.clone().or_else(|| fallback.clone())
+        };
+        Self {
+            low_battery: pick(&self.low_battery, &fallback.low_battery),
+            critical_battery: pick(&self.critical_battery, &fallback.critical_battery),
+            charged: pick(&self.charged, &fallback.charged),
+            charging_started: pick(&self.charging_started, &fallback.charging_started),
+            connected: pick(&self.connected, &fallback.connected),
+            disconnected: pick(&self.disconnected, &fallback.disconnected),
+            lost_while_low: pick(&self.lost_while_low, &fallback.lost_while_low),
+        }
+    }
 }
 
 // Default settings for debug mode
@@ -15,8 +247,18 @@ pub struct Settings {
 impl Default for Settings {
     fn default() -> Self {
         Self {
+            version: SETTINGS_VERSION,
             notifications: true,
+            desktop_notifications: false,
             debug: true,
+            alerts: AlertSettings::default(),
+            events: NotificationEvents::default(),
+            quiet: QuietSettings::default(),
+            templates: MessageTemplates::default(),
+            controllers: BTreeMap::new(),
+            webhooks: Vec::new(),
+            mqtt: None,
+            extra: Map::new(),
         }
     }
 }
@@ -26,45 +268,362 @@ impl Default for Settings {
 impl Default for Settings {
     fn default() -> Self {
         Self {
+            version: SETTINGS_VERSION,
             notifications: true,
+            desktop_notifications: false,
             debug: false,
+            alerts: AlertSettings::default(),
+            events: NotificationEvents::default(),
+            quiet: QuietSettings::default(),
+            templates: MessageTemplates::default(),
+            controllers: BTreeMap::new(),
+            webhooks: Vec::new(),
+            mqtt: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+// Alerts repeat within minutes in debug mode, so they can be tested without waiting
+#[cfg(debug_assertions)]
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            warning_threshold: 20,
+            critical_threshold: 10,
+            warning_repeat_minutes: 2,
+            critical_repeat_minutes: 1,
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            warning_threshold: 20,
+            critical_threshold: 10,
+            warning_repeat_minutes: 60,
+            critical_repeat_minutes: 15,
+        }
+    }
+}
+
+impl Settings {
+    pub fn nickname(&self, stable_id: &str) -> Option<&str> {
+        self.controllers.get(stable_id)?.nickname.as_deref()
+    }
+
+    /// The message templates of a controller, its own applied over the global ones
+    pub fn templates_for(&self, stable_id: &str) -> MessageTemplates {
+        match self.controllers.get(stable_id) {
+            Some(controller) => controller.templates.or(&self.templates),
+            None => self.templates.clone(),
+        }
+    }
+
+    /// The alert settings of a controller, its overrides applied over the global settings
+    pub fn alerts_for(&self, stable_id: &str) -> AlertSettings {
+        let global = self.alerts;
+        let Some(overrides) = self.controllers.get(stable_id) else {
+            return global;
+        };
+        AlertSettings {
+            warning_threshold: overrides
+                .warning_threshold
+                .unwrap_or(global.warning_threshold),
+            critical_threshold: overrides
+                .critical_threshold
+                .unwrap_or(global.critical_threshold),
+            warning_repeat_minutes: overrides
+                .warning_repeat_minutes
+                .unwrap_or(global.warning_repeat_minutes),
+            critical_repeat_minutes: overrides
+                .critical_repeat_minutes
+                .unwrap_or(global.critical_repeat_minutes),
+        }
+    }
+
+    /// The settings with their secrets replaced by `REDACTED`, to send them over the API
+    pub fn redacted(&self) -> Self {
+        let redact = |secret: &mut Option<String>| {
+            if secret.is_some() {
+                *secret = Some(REDACTED.to_string());
+            }
+        };
+        let mut settings = self.clone();
+        for webhook in &mut settings.webhooks {
+            redact(&mut webhook.secret);
+        }
+        if let Some(mqtt) = &mut settings.mqtt {
+            redact(&mut mqtt.password);
+        }
+        settings
+    }
+
+    /// Puts back the secrets of `current` where these settings have `REDACTED`, e.g. when a client
+    /// saves the settings it got from the API. Webhooks are matched by their URL.
+    fn keep_secrets(&mut self, current: &Settings) {
+        for webhook in &mut self.webhooks {
+            if webhook.secret.as_deref() == Some(REDACTED) {
+                webhook.secret = current
+                    .webhooks
+                    .iter()
+                    .find(|current| current.url == webhook.url)
+                    .and_then(|current| current.secret.clone());
+            }
+        }
+        if let Some(mqtt) = &mut self.mqtt {
+            if mqtt.password.as_deref() == Some(REDACTED) {
+                mqtt.password = current.mqtt.as_ref().and_then(|mqtt| mqtt.password.clone());
+            }
         }
     }
 }
 
 pub struct SettingsService {
-    settings: Mutex<Settings>,
+    file_path: PathBuf,
+    settings: watch::Sender<Settings>,
+    // Serializes writes so two requests can't interleave their temp files
+    write_lock: Mutex<()>,
 }
 
 impl SettingsService {
-    pub async fn new(file_path: &String) -> Result<Self> {
-        let file = File::open(file_path).await;
-        let settings = if let Ok(file) = file {
-            match serde_json::from_reader(file.into_std().await) {
-                Ok(settings) => settings,
+    pub async fn new(file_path: impl AsRef<Path>) -> Result<Self> {
+        let file_path = file_path.as_ref();
+        let mut save_file = false;
+        let settings = match tokio::fs::read(file_path).await {
+            Ok(contents) => match parse_settings(&contents) {
+                Ok((settings, migrated)) => {
+                    save_file = migrated;
+                    settings
+                }
                 Err(err) => {
-                    error!("Resetting config file due to parse failure: {}", err);
+                    // Leave the file alone, the user may want to fix it by hand
+                    error!(
+                        "Using default settings, failed to parse config file: {:#}",
+                        err
+                    );
                     Settings::default()
                 }
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                info!("Creating config file {}", file_path.display());
+                save_file = true;
+                Settings::default()
+            }
+            // Defaults would overwrite a file we only failed to read
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", file_path.display()))
             }
-        } else {
-            Settings::default()
         };
 
-        Ok(Self {
-            settings: Mutex::new(settings),
-        })
+        let service = Self {
+            file_path: PathBuf::from(file_path),
+            settings: watch::Sender::new(settings.clone()),
+            write_lock: Mutex::new(()),
+        };
+        if save_file {
+            service.save(&settings).await?;
+        }
+
+        Ok(service)
     }
 
     pub async fn get_settings(&self) -> Settings {
-        let settings = match self.settings.lock() {
-            Ok(settings) => settings,
+        self.settings.borrow().clone()
+    }
+
+    /// Returns a receiver that is notified every time the settings change
+    pub fn subscribe(&self) -> watch::Receiver<Settings> {
+        self.settings.subscribe()
+    }
+
+    /// Persists `settings` and makes them the current settings. Secrets left as `REDACTED` keep
+    /// their current value.
+    pub async fn set_settings(&self, mut settings: Settings) -> Result<Settings> {
+        settings.keep_secrets(&self.settings.borrow());
+        self.save(&settings).await?;
+        self.settings.send_if_modified(|current| {
+            let modified = *current != settings;
+            *current = settings.clone();
+            modified
+        });
+        Ok(settings)
+    }
+
+    /// Applies a JSON merge patch (RFC 7386) to the current settings. The result has to
+    /// deserialize into valid `Settings`, otherwise nothing is changed.
+    pub async fn patch_settings(&self, patch: &Value) -> Result<Settings, PatchError> {
+        let current = self.get_settings().await;
+        let mut merged =
+            serde_json::to_value(&current).map_err(|err| PatchError::Save(err.into()))?;
+        merge_patch(&mut merged, patch);
+        let mut settings: Settings = serde_json::from_value(merged).map_err(PatchError::Invalid)?;
+        // The schema version is ours to manage
+        settings.version = current.version;
+        self.set_settings(settings).await.map_err(PatchError::Save)
+    }
+
+    /// Reloads the settings every time the config file changes on disk, e.g. when it's edited by
+    /// hand. Runs until the watch fails.
+    pub async fn watch_file(&self) -> Result<()> {
+        let directory = match self.file_path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory,
+            _ => Path::new("."),
+        };
+        let file_name = self
+            .file_path
+            .file_name()
+            .context("Invalid config file path")?;
+
+        // Watch the directory rather than the file, the file is replaced on every atomic save
+        let inotify = Inotify::init()?;
+        inotify
+            .watches()
+            .add(directory, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+            .with_context(|| format!("Failed to watch {}", directory.display()))?;
+        info!("Watching {} for changes", self.file_path.display());
+
+        let mut buffer = [0; 1024];
+        let mut events = inotify.into_event_stream(&mut buffer)?;
+        while let Some(event) = events.next().await {
+            let event = event?;
+            if event.name.as_deref() == Some(file_name) {
+                self.reload().await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the config file again. Parse errors are logged and the current settings are kept.
+    async fn reload(&self) {
+        let contents = match tokio::fs::read(&self.file_path).await {
+            Ok(contents) => contents,
             Err(err) => {
-                error!("Failed to get lock for settings: {}", err);
-                return Settings::default();
+                error!(
+                    "Keeping current settings, failed to read config file: {}",
+                    err
+                );
+                return;
             }
         };
-        settings.clone()
+        let (settings, migrated) = match parse_settings(&contents) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                error!(
+                    "Keeping current settings, failed to parse config file: {:#}",
+                    err
+                );
+                return;
+            }
+        };
+
+        if migrated {
+            // Someone wrote an old schema, e.g. an older frontend. Store it upgraded.
+            if let Err(err) = self.save(&settings).await {
+                error!("Failed to save migrated settings: {:#}", err);
+            }
+        }
+
+        let modified = self.settings.send_if_modified(|current| {
+            let modified = *current != settings;
+            *current = settings;
+            modified
+        });
+        if modified {
+            info!("Reloaded settings from {}", self.file_path.display());
+        } else {
+            debug!("Config file changed but settings are the same");
+        }
+    }
+
+    /// Writes the settings to a temporary file next to the config file and renames it over the
+    /// config file, so readers never see a partially written file.
+    async fn save(&self, settings: &Settings) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let contents = serde_json::to_vec_pretty(settings)?;
+        let tmp_path = tmp_path(&self.file_path);
+
+        let mut file = File::create(&tmp_path)
+            .await
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        file.write_all(&contents).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&tmp_path, &self.file_path)
+            .await
+            .with_context(|| format!("Failed to replace {}", self.file_path.display()))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// The patched settings aren't valid
+    Invalid(serde_json::Error),
+    Save(anyhow::Error),
+}
+
+/// Parses a config file and upgrades it to the current schema. Options missing from the file get
+/// their default value. Also returns whether the file was migrated and should be saved again.
+fn parse_settings(contents: &[u8]) -> Result<(Settings, bool)> {
+    let mut value: Value = serde_json::from_slice(contents)?;
+    let object = value
+        .as_object_mut()
+        .context("Config file doesn't contain a JSON object")?;
+
+    // Files written before the schema was versioned don't have a version
+    let version = match object.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .context("Invalid settings version")?,
+    };
+    if version > SETTINGS_VERSION {
+        warn!(
+            "Config file has version {}, newer than {}. Unknown options are kept as-is.",
+            version, SETTINGS_VERSION
+        );
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        debug!("Migrating settings from version {} to {}", from, from + 1);
+        migration(object);
+        object.insert("version".to_string(), Value::from(from + 1));
+    }
+
+    let settings = serde_json::from_value(value)?;
+    Ok((settings, version < SETTINGS_VERSION))
+}
+
+/// The first versioned schema has the same options, files only gain the version field
+fn migrate_v0_to_v1(_settings: &mut Map<String, Value>) {}
+
+fn tmp_path(file_path: &Path) -> PathBuf {
+    let mut file_name = file_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    file_path.with_file_name(file_name)
+}
+
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
     }
 }
 
@@ -85,8 +644,9 @@ mod test {
         assert!(tokio::fs::metadata(&file_path).await.is_ok());
 
         let mut settings = settings_service.get_settings().await;
-        assert_eq!(settings.notifications, true);
+        assert!(settings.notifications);
         settings.notifications = false;
+        settings_service.set_settings(settings).await?;
 
         // Read it again
         let settings_service = SettingsService::new(&file_path).await?;
@@ -95,6 +655,244 @@ mod test {
 
         // Delete the config file
         tokio::fs::remove_file(file_path).await?;
+
+        // A config file we can't read isn't replaced with the defaults
+        assert!(SettingsService::new("/tmp").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_patch_settings() -> anyhow::Result<()> {
+        use crate::settings::{AlertSettings, NotificationEvents, PatchError, SettingsService};
+        use serde_json::json;
+        use std::time::SystemTime;
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        let file_path = format!("/tmp/test_patch_settings_{}.json", timestamp);
+        let settings_service = SettingsService::new(&file_path).await?;
+        let mut changes = settings_service.subscribe();
+
+        let settings = settings_service
+            .patch_settings(&json!({ "debug": false }))
+            .await
+            .unwrap();
+        assert!(!settings.debug);
+        assert!(settings.notifications);
+        assert!(changes.has_changed()?);
+        assert!(!changes.borrow_and_update().debug);
+
+        // Invalid values are rejected and leave the settings untouched
+        let result = settings_service
+            .patch_settings(&json!({ "notifications": "yes" }))
+            .await;
+        assert!(matches!(result, Err(PatchError::Invalid(_))));
+        assert!(settings_service.get_settings().await.notifications);
+        assert!(!changes.has_changed()?);
+
+        let contents = tokio::fs::read_to_string(&file_path).await?;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&contents)?,
+            json!({
+                "version": 1,
+                "notifications": true,
+                "desktopNotifications": false,
+                "debug": false,
+                "alerts": AlertSettings::default(),
+                "events": NotificationEvents::default(),
+                "quiet": { "schedule": [], "suppressed": "digest" },
+                "templates": {},
+                "controllers": {},
+                "webhooks": [],
+            })
+        );
+
+        tokio::fs::remove_file(file_path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secrets() -> anyhow::Result<()> {
+        use crate::settings::{SettingsService, REDACTED};
+        use serde_json::json;
+        use std::time::SystemTime;
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        let file_path = format!("/tmp/test_secret_settings_{}.json", timestamp);
+        let settings_service = SettingsService::new(&file_path).await?;
+        settings_service
+            .patch_settings(&json!({
+                "webhooks": [{ "url": "http://hass.local/hook", "secret": "hunter2" }],
+                "mqtt": { "username": "decky", "password": "correct horse" },
+            }))
+            .await
+            .unwrap();
+
+        let redacted = settings_service.get_settings().await.redacted();
+        let json = serde_json::to_value(&redacted)?;
+        assert_eq!(json["webhooks"][0]["secret"], REDACTED);
+        assert_eq!(json["mqtt"]["password"], REDACTED);
+        assert_eq!(json["mqtt"]["username"], "decky");
+
+        // Sending back what the API returned keeps the secrets
+        let settings = settings_service.patch_settings(&json).await.unwrap();
+        assert_eq!(settings.webhooks[0].secret.as_deref(), Some("hunter2"));
+        let mqtt = settings.mqtt.unwrap();
+        assert_eq!(mqtt.password.as_deref(), Some("correct horse"));
+        let settings = settings_service.set_settings(redacted).await?;
+        assert_eq!(settings.webhooks[0].secret.as_deref(), Some("hunter2"));
+
+        // The placeholder doesn't carry a secret over to another webhook
+        let settings = settings_service
+            .patch_settings(&json!({
+                "webhooks": [{ "url": "http://other.local/hook", "secret": REDACTED }],
+            }))
+            .await
+            .unwrap();
+        assert_eq!(settings.webhooks[0].secret, None);
+
+        tokio::fs::remove_file(file_path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_file() -> anyhow::Result<()> {
+        use crate::settings::SettingsService;
+        use std::{sync::Arc, time::Duration, time::SystemTime};
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        let file_path = format!("/tmp/test_watch_settings_{}.json", timestamp);
+        let settings_service = Arc::new(SettingsService::new(&file_path).await?);
+        let mut changes = settings_service.subscribe();
+
+        let watcher = settings_service.clone();
+        let watch_task = tokio::spawn(async move { watcher.watch_file().await });
+        // Give the watcher a moment to register before writing
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Written in place, like Python's `json.dump` does
+        tokio::fs::write(&file_path, r#"{"notifications": false, "debug": true}"#).await?;
+        tokio::time::timeout(Duration::from_secs(5), changes.changed()).await??;
+        assert!(!changes.borrow_and_update().notifications);
+
+        // A broken file keeps the last good settings
+        tokio::fs::write(&file_path, "{").await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!changes.has_changed()?);
+        assert!(!settings_service.get_settings().await.notifications);
+
+        watch_task.abort();
+        tokio::fs::remove_file(file_path).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_settings() -> anyhow::Result<()> {
+        use crate::settings::{parse_settings, Settings, SETTINGS_VERSION};
+        use serde_json::json;
+
+        // Files written before versioning are migrated
+        let (settings, migrated) = parse_settings(br#"{"notifications": false, "debug": true}"#)?;
+        assert!(migrated);
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert!(!settings.notifications);
+
+        // Missing options get their default, unknown ones are kept
+        let (settings, migrated) = parse_settings(br#"{"version": 1, "nickname": "Pad"}"#)?;
+        assert!(!migrated);
+        assert_eq!(settings.notifications, Settings::default().notifications);
+        assert_eq!(serde_json::to_value(&settings)?["nickname"], json!("Pad"));
+
+        // Files from a newer version are read but not migrated or downgraded
+        let (settings, migrated) = parse_settings(br#"{"version": 99, "debug": false}"#)?;
+        assert!(!migrated);
+        assert_eq!(settings.version, 99);
+        assert!(!settings.debug);
+
+        assert!(parse_settings(br#"{"debug": "yes"}"#).is_err());
+        assert!(parse_settings(b"[]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_alerts_for() -> anyhow::Result<()> {
+        use crate::settings::Settings;
+
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "alerts": { "warningThreshold": 30, "criticalRepeatMinutes": 5 },
+                "templates": { "lowBattery": "{nickname} needs a charge", "charged": "Full" },
+                "controllers": {
+                    "057e-2009": {
+                        "warningThreshold": 50,
+                        "nickname": "Pro",
+                        "templates": { "lowBattery": "{nickname} is at {capacity}%" }
+                    }
+                }
+            }"#,
+        )?;
+        let alerts = settings.alerts_for("054c-0ce6");
+        assert_eq!(alerts.warning_threshold, 30);
+        assert_eq!(alerts.critical_threshold, 10);
+        assert_eq!(alerts.critical_repeat_minutes, 5);
+
+        let alerts = settings.alerts_for("057e-2009");
+        assert_eq!(alerts.warning_threshold, 50);
+        assert_eq!(alerts.critical_repeat_minutes, 5);
+
+        assert_eq!(settings.nickname("057e-2009"), Some("Pro"));
+        assert_eq!(settings.nickname("054c-0ce6"), None);
+        let templates = settings.templates_for("057e-2009");
+        assert_eq!(
+            templates.low_battery.as_deref(),
+            Some("{nickname} is at {capacity}%")
+        );
+        assert_eq!(templates.charged.as_deref(), Some("Full"));
+        let templates = settings.templates_for("054c-0ce6");
+        assert_eq!(
+            templates.low_battery.as_deref(),
+            Some("{nickname} needs a charge")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_quiet_hours() -> anyhow::Result<()> {
+        use crate::settings::QuietSettings;
+        use chrono::{TimeZone, Utc};
+
+        let quiet: QuietSettings = serde_json::from_str(
+            r#"{
+                "schedule": [
+                    { "days": ["Fri", "Sat"], "start": "22:00", "end": "07:00" },
+                    { "start": "13:00:00", "end": "14:00:00" }
+                ]
+            }"#,
+        )?;
+
+        // 2026-10-16 is a Friday
+        let at = |day, hour, minute| {
+            Utc.with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+                .unwrap()
+        };
+        assert!(!quiet.is_quiet(&at(16, 21, 59)));
+        assert!(quiet.is_quiet(&at(16, 22, 0)));
+        // Past midnight, the period started on Friday
+        assert!(quiet.is_quiet(&at(17, 6, 59)));
+        assert!(!quiet.is_quiet(&at(17, 7, 0)));
+        // Thursday night isn't quiet, so Friday morning isn't either
+        assert!(!quiet.is_quiet(&at(16, 6, 0)));
+        // Sunday morning, the period started on Saturday
+        assert!(quiet.is_quiet(&at(18, 3, 0)));
+        assert!(!quiet.is_quiet(&at(19, 3, 0)));
+        // Every day
+        assert!(quiet.is_quiet(&at(14, 13, 30)));
+        assert!(!quiet.is_quiet(&at(14, 14, 0)));
         Ok(())
     }
 }
@@ -0,0 +1,267 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use log::{debug, error, warn};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{protocol::ServerMessage, settings::WebhookSettings, AppState};
+
+// Attempts to deliver an alert before giving up, the delay between them doubles every time
+const MAX_ATTEMPTS: u32 = 5;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the webhook's secret
+pub const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+/// Posts every alert published by the monitor to the webhooks of the settings that want it. The
+/// body is the same envelope the websocket clients get, e.g.
+/// `{"version": 1, "type": "low_battery", "payload": {...}}`.
+pub async fn run(state: Arc<AppState>) -> Result<()> {
+    let client = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+        .context("Failed to create the HTTP client")?;
+    let mut alerts = state.alert_service.subscribe();
+
+    loop {
+        let alerts = match alerts.recv().await {
+            Ok(alerts) => alerts,
+            Err(RecvError::Lagged(missed)) => {
+                error!("Webhooks missed {} batches of alerts", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let webhooks = state.settings_service.get_settings().await.webhooks;
+        // Grouping is for notifications, webhooks get the alerts one by one
+        for message in alerts.iter().flat_map(ServerMessage::ungroup) {
+            for webhook in webhooks.iter().filter(|webhook| wants(webhook, message)) {
+                // Retries can take a while, don't hold back the other webhooks and alerts
+                let client = client.clone();
+                let webhook = webhook.clone();
+                let message = message.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = deliver(&client, &webhook, &message, FIRST_RETRY_DELAY).await
+                    {
+                        error!("Failed to call webhook {}: {:#}", webhook.url, err);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Whether the webhook is interested in the message, it gets every kind of alert unless it lists
+/// some
+fn wants(webhook: &WebhookSettings, message: &ServerMessage) -> bool {
+    message
+        .event_kind()
+        .is_some_and(|kind| webhook.events.is_empty() || webhook.events.contains(&kind))
+}
+
+/// Posts the message to the webhook, retrying with an exponential backoff starting at
+/// `retry_delay` while the endpoint is unreachable or fails on its side
+async fn deliver(
+    client: &Client,
+    webhook: &WebhookSettings,
+    message: &ServerMessage,
+    retry_delay: Duration,
+) -> Result<()> {
+    let body = message.to_json();
+    let mut delay = retry_delay;
+    let mut attempt = 1;
+    loop {
+        let mut request = client
+            .post(&webhook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(secret) = &webhook.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, body.as_bytes()));
+        }
+
+        let err = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("Webhook {} answered {}", webhook.url, response.status());
+                return Ok(());
+            }
+            // Sending the same request again won't change the answer
+            Ok(response)
+                if response.status().is_client_error()
+                    && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                return Err(anyhow!("Webhook answered {}", response.status()));
+            }
+            Ok(response) => anyhow!("Webhook answered {}", response.status()),
+            Err(err) => err.into(),
+        };
+        if attempt == MAX_ATTEMPTS {
+            return Err(err.context(format!("Gave up after {} attempts", MAX_ATTEMPTS)));
+        }
+        warn!(
+            "Webhook {} failed, retrying in {:?}: {:#}",
+            webhook.url, delay, err
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Value of the signature header for a body, so the receiver can check it came from us
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use reqwest::Client;
+
+    use super::{deliver, signature, wants, SIGNATURE_HEADER};
+    use crate::controller::{Controller, Status};
+    use crate::protocol::{BatteryAlert, EventKind, ServerMessage};
+    use crate::settings::WebhookSettings;
+
+    #[derive(Default)]
+    struct Listener {
+        // Answers to give, in order, then 200
+        statuses: Vec<StatusCode>,
+        // Body and signature of every request received
+        requests: Vec<(String, Option<String>)>,
+    }
+
+    /// Starts a local HTTP server for the webhooks and returns its URL
+    async fn listen(listener: Arc<Mutex<Listener>>) -> String {
+        async fn receive(
+            State(listener): State<Arc<Mutex<Listener>>>,
+            headers: HeaderMap,
+            body: String,
+        ) -> StatusCode {
+            let mut listener = listener.lock().unwrap();
+            let signature = headers
+                .get(SIGNATURE_HEADER)
+                .map(|value| value.to_str().unwrap().to_string());
+            listener.requests.push((body, signature));
+            match listener.statuses.is_empty() {
+                true => StatusCode::OK,
+                false => listener.statuses.remove(0),
+            }
+        }
+
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(listener);
+        let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", tcp.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(tcp, app).await });
+        url
+    }
+
+    fn low_battery() -> ServerMessage {
+        let controller = Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity: 15,
+            status: Status::Discharging,
+            bluetooth: true,
+            serial_number: None,
+            device_path: Some("/dev/hidraw1".to_string()),
+        };
+        let message = "DualSense is low on battery (15%)".to_string();
+        ServerMessage::LowBattery(BatteryAlert::new(&controller, message))
+    }
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(
+            signature("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_wants() {
+        let mut webhook = WebhookSettings {
+            url: "http://localhost/hook".to_string(),
+            events: Vec::new(),
+            secret: None,
+        };
+        assert!(wants(&webhook, &low_battery()));
+        webhook.events = vec![EventKind::Charged];
+        assert!(!wants(&webhook, &low_battery()));
+        webhook.events.push(EventKind::LowBattery);
+        assert!(wants(&webhook, &low_battery()));
+    }
+
+    #[tokio::test]
+    async fn test_deliver() -> anyhow::Result<()> {
+        let client = Client::new();
+        let retry_delay = Duration::from_millis(10);
+        let listener = Arc::new(Mutex::new(Listener {
+            statuses: vec![
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::TOO_MANY_REQUESTS,
+            ],
+            ..Default::default()
+        }));
+        let webhook = WebhookSettings {
+            url: listen(listener.clone()).await,
+            events: Vec::new(),
+            secret: Some("secret".to_string()),
+        };
+
+        // Retried until the endpoint accepts it
+        let message = low_battery();
+        deliver(&client, &webhook, &message, retry_delay).await?;
+        let requests = std::mem::take(&mut listener.lock().unwrap().requests);
+        assert_eq!(requests.len(), 3);
+        let (body, signature) = &requests[2];
+        assert_eq!(body, &message.to_json());
+        assert_eq!(
+            signature.as_deref(),
+            Some(super::signature("secret", body.as_bytes()).as_str())
+        );
+
+        // A rejected alert isn't sent again
+        listener.lock().unwrap().statuses = vec![StatusCode::BAD_REQUEST];
+        assert!(deliver(&client, &webhook, &message, retry_delay)
+            .await
+            .is_err());
+        assert_eq!(listener.lock().unwrap().requests.len(), 1);
+
+        // Nobody listening
+        let webhook = WebhookSettings {
+            url: "http://127.0.0.1:9/hook".to_string(),
+            events: Vec::new(),
+            secret: None,
+        };
+        assert!(deliver(&client, &webhook, &message, retry_delay)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -5,6 +5,7 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::Result;
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
@@ -13,23 +14,33 @@ use axum::{
     response::IntoResponse,
 };
 
-use futures::stream::StreamExt;
-use futures::SinkExt;
 use log::{debug, error, info};
+use tokio::{
+    sync::{broadcast::error::RecvError, oneshot, watch},
+    time::Instant,
+};
 
-use crate::{api, AppState};
+use crate::{
+    controller::Controller,
+    estimate::Estimate,
+    metrics,
+    monitor::{group_alerts, BATTERY_CHECK_INTERVAL},
+    protocol::{
+        ClientHello, ClientMessage, CommandResult, ControllerState, Envelope, ErrorCode,
+        ErrorPayload, PollInterval, ServerHello, ServerMessage, Snapshot, Subscriptions,
+        PROTOCOL_VERSION,
+    },
+    settings::Settings,
+    AppState,
+};
 
-// How often to check the battery level
-#[cfg(not(debug_assertions))]
-const BATTERY_CHECK_INTERVAL: Duration = std::time::Duration::from_secs(60);
-#[cfg(debug_assertions)]
-const BATTERY_CHECK_INTERVAL: Duration = std::time::Duration::from_secs(10);
+// Bounds for the poll interval a client can ask for. The monitor doesn't check the controllers
+// more often than every `BATTERY_CHECK_INTERVAL`, a shorter interval wouldn't change anything.
+const MIN_POLL_INTERVAL: Duration = BATTERY_CHECK_INTERVAL;
+const MAX_POLL_INTERVAL: Duration = std::time::Duration::from_secs(60 * 60);
 
-// How often to send a notification to the client
-#[cfg(not(debug_assertions))]
-const BATTERY_ALERT_INTERVAL: Duration = std::time::Duration::from_secs(60 * 60);
-#[cfg(debug_assertions)]
-const BATTERY_ALERT_INTERVAL: Duration = std::time::Duration::from_secs(60);
+// How long a client has to answer our hello
+const HANDSHAKE_TIMEOUT: Duration = std::time::Duration::from_secs(10);
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
@@ -40,134 +51,437 @@ pub async fn ws_handler(
 
 /// Actual websocket statemachine (one will be spawned per connection)
 async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
-    // Send a ping to kick things off and get a response
-    if socket.send(Message::Ping(vec![1, 2, 3])).await.is_ok() {
-        debug!("Pinged!");
-    } else {
-        debug!("Could not send ping to client");
+    // Introduce ourselves, the client has to answer with its own hello before we send anything else
+    let hello = ServerMessage::Hello(ServerHello::default());
+    if socket.send(Message::Text(hello.to_json())).await.is_err() {
+        debug!("Could not send hello to client");
         // no Error here since the only thing we can do is to close the connection.
         // If we can not send messages, there is no way to salvage the statemachine anyway.
         return;
     }
 
-    // receive single message from a client (we can either receive or send with socket).
-    // this will likely be the Pong for our Ping or a hello message from client.
     // waiting for message from a client will block this task, but will not block other client's
     // connections.
-    if let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            if process_message(msg).is_break() {
-                return;
-            }
-        } else {
-            debug!("client abruptly disconnected");
+    let handshake = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut socket)).await {
+        Ok(handshake) => handshake,
+        Err(_) => Err(ErrorPayload::new(
+            ErrorCode::HandshakeFailed,
+            "Timed out waiting for hello",
+        )),
+    };
+    match handshake {
+        Ok(hello) => {
+            info!(
+                "Client {} connected",
+                hello.client.as_deref().unwrap_or("unknown")
+            );
+        }
+        Err(error) => {
+            debug!("Handshake failed: {:?}", error);
+            // Best effort, the client may already be gone
+            let message = ServerMessage::Error(error);
+            let _ = socket.send(Message::Text(message.to_json())).await;
             return;
         }
     }
+    let _client = metrics::global().websocket_client();
 
-    // By splitting, we can send and receive at the same time.
-    let (mut sender, mut receiver) = socket.split();
+    // Send the client what changed with each check of the monitor, at most once per poll
+    // interval. Alerts, e.g. when a controller is low on battery or done charging, and the errors
+    // of failed checks come from the same monitor shared by every client. In between, answer the
+    // commands sent by the client.
+    // The first update is sent right away so the client gets a snapshot without waiting for the
+    // next check.
+    let mut alerts = state.alert_service.subscribe();
+    let mut session = Session::new(state);
+    let mut last_update: Option<Instant> = None;
+    let mut pending = true;
+    let mut cnt = 0;
 
-    // This task will check controllers every 30 seconds and send a message to client if
-    // a controller is low on battery
-    let mut send_task = tokio::spawn(async move {
-        // HashMap to store last alert timestamps for each controller
-        let mut last_alerts: HashMap<String, u64> = HashMap::new();
-        let mut cnt = 0;
-
-        loop {
-            tokio::time::sleep(BATTERY_CHECK_INTERVAL).await;
-
-            let settings = state.settings_service.get_settings().await;
-            if !settings.notifications {
-                debug!("Notifications disabled, skipping notification check...");
+    'session: loop {
+        let next_update =
+            last_update.map_or_else(Instant::now, |last| last + session.poll_interval);
+        let replies = tokio::select! {
+            _ = tokio::time::sleep_until(next_update), if pending => {
+                pending = false;
+                last_update = Some(Instant::now());
+                let controllers = session.controllers.borrow_and_update().clone();
+                let messages = session.update(controllers).await;
+                messages.iter().map(ServerMessage::to_json).collect()
+            }
+            changed = session.controllers.changed(), if !pending => {
+                if changed.is_err() {
+                    break;
+                }
+                pending = true;
                 continue;
             }
+            msg = socket.recv() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    _ => {
+                        debug!("client abruptly disconnected");
+                        break;
+                    }
+                };
+                let text = match msg {
+                    Message::Text(text) => text,
+                    msg => {
+                        if process_message(msg).is_break() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                debug!(">>> client sent str: {:?}", text);
 
-            debug!("Checking controllers...");
-            let controllers = match api::controllers_async().await {
-                Ok(controllers) => controllers,
-                Err(e) => {
-                    error!("Error getting controllers: {}", e);
+                session.handle_text(&text).await
+            }
+            alerts = alerts.recv() => match alerts {
+                Ok(alerts) => {
+                    let messages = session.alerts(&alerts);
+                    messages.iter().map(ServerMessage::to_json).collect()
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    error!("Websocket client missed {} batches of alerts", missed);
                     continue;
                 }
-            };
+                Err(RecvError::Closed) => break,
+            },
+        };
 
-            for controller in controllers {
-                let low_battery = controller.capacity < 20 && controller.is_discharging();
-                debug!(
-                    "Controller {} is low battery: {}",
-                    controller.name, low_battery
-                );
-                if low_battery {
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-
-                    let first_alert = !last_alerts.contains_key(&controller.id());
-
-                    let last_alert = last_alerts.entry(controller.id()).or_insert(now);
-
-                    let last_alert_secs_ago = now - *last_alert;
-                    debug!(
-                        "Last alert was {} seconds ago for controller {}",
-                        last_alert_secs_ago, controller.name
-                    );
-
-                    if first_alert || last_alert_secs_ago >= BATTERY_ALERT_INTERVAL.as_secs() {
-                        let message = format!(
-                            "{} is low on battery ({}%)",
-                            controller.name, controller.capacity
-                        );
-                        info!("Sending notification: {}", message);
-
-                        if sender.send(Message::Text(message)).await.is_ok() {
-                            cnt += 1;
-                        } else {
-                            return cnt;
-                        }
+        for reply in replies {
+            if socket.send(Message::Text(reply)).await.is_err() {
+                break 'session;
+            }
+            cnt += 1;
+        }
+    }
 
-                        // Update last alert timestamp
-                        *last_alert = now;
+    // returning from the handler closes the websocket connection
+    info!("Websocket context destroyed after sending {} messages", cnt);
+}
+
+/// State of a single client connection
+struct Session {
+    state: Arc<AppState>,
+    settings: watch::Receiver<Settings>,
+    // Controllers found by the monitor's checks
+    controllers: watch::Receiver<Vec<Controller>>,
+    poll_interval: Duration,
+    subscriptions: Subscriptions,
+    // Controllers of the last update, used to tell the client what changed
+    previous: Option<Vec<Controller>>,
+    // Time left estimates from the last check, keyed by stable id
+    estimates: HashMap<String, Estimate>,
+}
+
+impl Session {
+    fn new(state: Arc<AppState>) -> Self {
+        let settings = state.settings_service.subscribe();
+        let controllers = state.controllers.subscribe();
+        Self {
+            state,
+            settings,
+            controllers,
+            poll_interval: BATTERY_CHECK_INTERVAL,
+            subscriptions: Subscriptions::default(),
+            previous: None,
+            estimates: HashMap::new(),
+        }
+    }
+
+    /// Takes the controllers found by a check of the monitor and returns the messages this
+    /// session is subscribed to
+    async fn update(&mut self, controllers: Vec<Controller>) -> Vec<ServerMessage> {
+        self.estimates = self.state.history_service.estimates(&controllers).await;
+
+        let mut messages = match &self.previous {
+            // The first successful check gives the client the full picture, after that only
+            // the changes since the previous one
+            None => vec![ServerMessage::Snapshot(self.snapshot(&controllers))],
+            Some(previous) => controller_events(previous, &controllers),
+        };
+
+        self.previous = Some(controllers);
+
+        for message in &mut messages {
+            if let ServerMessage::Connected(state) | ServerMessage::ControllerUpdated(state) =
+                message
+            {
+                state.estimate = self.estimates.get(&state.stable_id).copied();
+            }
+        }
+        messages.retain(|message| self.subscriptions.wants(message));
+        messages
+    }
+
+    /// The alerts of a check of the monitor this session is subscribed to
+    fn alerts(&self, alerts: &[ServerMessage]) -> Vec<ServerMessage> {
+        let ungrouped: Vec<&ServerMessage> =
+            alerts.iter().flat_map(ServerMessage::ungroup).collect();
+        let messages = if ungrouped
+            .iter()
+            .all(|message| self.subscriptions.wants(message))
+        {
+            alerts.to_vec()
+        } else {
+            // Group what's left once the alerts the session isn't subscribed to are gone
+            let messages = ungrouped
+                .into_iter()
+                .filter(|message| self.subscriptions.wants(message))
+                .cloned()
+                .collect();
+            group_alerts(messages, &self.settings.borrow())
+        };
+
+        for message in &messages {
+            match message {
+                ServerMessage::Digest(digest) => info!("Sending digest: {}", digest.message),
+                ServerMessage::AlertGroup(group) => {
+                    info!("Sending notification: {}", group.message)
+                }
+                message => {
+                    if let Some(alert) = message.alert() {
+                        info!("Sending notification: {}", alert.message);
                     }
                 }
             }
         }
-    });
+        messages
+    }
 
-    // This second task will receive messages from client and print them on server console
-    let mut recv_task = tokio::spawn(async move {
-        let mut cnt = 0;
-        while let Some(Ok(msg)) = receiver.next().await {
-            cnt += 1;
-            if process_message(msg).is_break() {
-                break;
+    fn snapshot(&self, controllers: &[Controller]) -> Snapshot {
+        Snapshot {
+            controllers: controllers
+                .iter()
+                .filter(|controller| self.subscriptions.wants_controller(&controller.id()))
+                .map(|controller| ControllerState {
+                    estimate: self.estimates.get(&controller.stable_id()).copied(),
+                    ..controller.into()
+                })
+                .collect(),
+        }
+    }
+
+    /// Handles a text message sent by the client and returns the serialized replies
+    async fn handle_text(&mut self, text: &str) -> Vec<String> {
+        let envelope: Envelope<ClientMessage> = match serde_json::from_str(text) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                let message = ServerMessage::error(
+                    ErrorCode::InvalidMessage,
+                    format!("Invalid message: {}", err),
+                );
+                return vec![message.to_json()];
             }
+        };
+        let id = envelope.id.as_deref();
+        if envelope.version != PROTOCOL_VERSION {
+            let message = ServerMessage::error(
+                ErrorCode::VersionMismatch,
+                format!(
+                    "Server speaks protocol version {}, client sent version {}",
+                    PROTOCOL_VERSION, envelope.version
+                ),
+            );
+            return vec![message.to_reply_json(id)];
         }
-        cnt
-    });
 
-    // If any one of the tasks exit, abort the other.
-    tokio::select! {
-        rv_a = (&mut send_task) => {
-            match rv_a {
-                Ok(cnt) => println!("Sent {} messages", cnt),
-                Err(err) => println!("Error sending messages {:?}", err)
+        let mut events = Vec::new();
+        let reply = match self.handle_command(envelope.message, &mut events).await {
+            Ok(result) => ServerMessage::Response(result),
+            Err(error) => ServerMessage::Error(error),
+        };
+
+        let mut replies: Vec<String> = events.iter().map(ServerMessage::to_json).collect();
+        replies.push(reply.to_reply_json(id));
+        replies
+    }
+
+    /// Runs a client command. Events caused by the command, e.g. by a refresh, are pushed to
+    /// `events` and sent ahead of the reply.
+    async fn handle_command(
+        &mut self,
+        command: ClientMessage,
+        events: &mut Vec<ServerMessage>,
+    ) -> Result<CommandResult, ErrorPayload> {
+        match command {
+            ClientMessage::Hello(_) => Err(ErrorPayload::new(
+                ErrorCode::InvalidCommand,
+                "Handshake already completed",
+            )),
+            ClientMessage::Refresh => {
+                // Have the monitor check right away instead of at its next tick, every client
+                // gets the result
+                let (request, result) = oneshot::channel();
+                // Without a monitor the request is dropped, which fails `result` right away
+                let _ = self.state.refresh.send(request);
+                let controllers = match result.await {
+                    Ok(Ok(controllers)) => controllers,
+                    Ok(Err(err)) => return Err(ErrorPayload::new(ErrorCode::ProbeFailed, err)),
+                    Err(_) => {
+                        return Err(ErrorPayload::new(
+                            ErrorCode::ProbeFailed,
+                            "The controllers aren't being checked",
+                        ))
+                    }
+                };
+                events.extend(self.update(controllers).await);
+                let controllers = self.previous.as_deref().unwrap_or_default();
+                Ok(CommandResult::Snapshot(self.snapshot(controllers)))
+            }
+            ClientMessage::Subscribe(change) => {
+                self.subscriptions.subscribe(change);
+                Ok(CommandResult::Subscriptions(self.subscriptions.clone()))
+            }
+            ClientMessage::Unsubscribe(change) => {
+                self.subscriptions.unsubscribe(change);
+                Ok(CommandResult::Subscriptions(self.subscriptions.clone()))
+            }
+            ClientMessage::AckAlert(target) => {
+                let stable_id = self.known_controller(&target.controller)?;
+                self.state
+                    .alert_service
+                    .update(|alerts| alerts.acknowledged.insert(stable_id))
+                    .await;
+                Ok(CommandResult::Done {})
+            }
+            ClientMessage::SnoozeAlert(snooze) => {
+                let stable_id = self.known_controller(&snooze.controller)?;
+                let until = unix_now() + snooze.minutes * 60;
+                self.state
+                    .alert_service
+                    .update(|alerts| alerts.snoozed_until.insert(stable_id, until))
+                    .await;
+                Ok(CommandResult::Done {})
+            }
+            ClientMessage::SnoozeAll(snooze) => {
+                let until = (snooze.minutes > 0).then(|| unix_now() + snooze.minutes * 60);
+                self.state
+                    .alert_service
+                    .update(|alerts| alerts.snoozed_all_until = until)
+                    .await;
+                Ok(CommandResult::Done {})
             }
-            recv_task.abort();
-        },
-        rv_b = (&mut recv_task) => {
-            match rv_b {
-                Ok(cnt) => println!("Received {} messages", cnt),
-                Err(b) => println!("Error receiving messages {:?}", b)
+            ClientMessage::SetPollInterval(interval) => {
+                let requested = Duration::from_secs(interval.seconds);
+                if !(MIN_POLL_INTERVAL..=MAX_POLL_INTERVAL).contains(&requested) {
+                    return Err(ErrorPayload::new(
+                        ErrorCode::InvalidCommand,
+                        format!(
+                            "Poll interval must be between {} and {} seconds",
+                            MIN_POLL_INTERVAL.as_secs(),
+                            MAX_POLL_INTERVAL.as_secs()
+                        ),
+                    ));
+                }
+                self.poll_interval = requested;
+                Ok(CommandResult::PollInterval(PollInterval {
+                    seconds: requested.as_secs(),
+                }))
             }
-            send_task.abort();
         }
     }
 
-    // returning from the handler closes the websocket connection
-    info!("Websocket context destroyed");
+    /// The stable id of the controller with the given id, alerts are kept by stable id
+    fn known_controller(&self, id: &str) -> Result<String, ErrorPayload> {
+        self.previous
+            .iter()
+            .flatten()
+            .find(|controller| controller.id() == id)
+            .map(Controller::stable_id)
+            .ok_or_else(|| {
+                ErrorPayload::new(
+                    ErrorCode::UnknownController,
+                    format!("No controller with id {}", id),
+                )
+            })
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Waits for the client's hello and checks that it speaks our protocol version
+async fn handshake(socket: &mut WebSocket) -> Result<ClientHello, ErrorPayload> {
+    while let Some(msg) = socket.recv().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(_) => break,
+        };
+        let text = match msg {
+            Message::Text(text) => text,
+            msg => {
+                if process_message(msg).is_break() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        // Check the version before looking at the message itself, a newer client may well send
+        // a hello we don't understand
+        let envelope: Envelope<serde_json::Value> = serde_json::from_str(&text).map_err(|err| {
+            ErrorPayload::new(
+                ErrorCode::HandshakeFailed,
+                format!("Invalid message: {}", err),
+            )
+        })?;
+        if envelope.version != PROTOCOL_VERSION {
+            return Err(ErrorPayload::new(
+                ErrorCode::VersionMismatch,
+                format!(
+                    "Server speaks protocol version {}, client sent version {}",
+                    PROTOCOL_VERSION, envelope.version
+                ),
+            ));
+        }
+        return match serde_json::from_value(envelope.message) {
+            Ok(ClientMessage::Hello(hello)) => Ok(hello),
+            Ok(_) => Err(ErrorPayload::new(
+                ErrorCode::HandshakeFailed,
+                "Expected hello before any command",
+            )),
+            Err(err) => Err(ErrorPayload::new(
+                ErrorCode::HandshakeFailed,
+                format!("Expected hello: {}", err),
+            )),
+        };
+    }
+
+    Err(ErrorPayload::new(
+        ErrorCode::HandshakeFailed,
+        "Client disconnected during handshake",
+    ))
+}
+
+/// Compares two consecutive checks and returns a message for every controller that connected,
+/// disconnected or changed in between
+fn controller_events(previous: &[Controller], current: &[Controller]) -> Vec<ServerMessage> {
+    let mut messages = Vec::new();
+
+    for controller in current {
+        match previous.iter().find(|p| p.id() == controller.id()) {
+            None => messages.push(ServerMessage::Connected(controller.into())),
+            Some(previous) if previous != controller => {
+                messages.push(ServerMessage::ControllerUpdated(controller.into()))
+            }
+            Some(_) => {}
+        }
+    }
+
+    for controller in previous {
+        if !current.iter().any(|c| c.id() == controller.id()) {
+            messages.push(ServerMessage::Disconnected(controller.into()));
+        }
+    }
+
+    messages
 }
 
 /// helper to print contents of messages to stdout. Has special treatment for Close.
@@ -202,3 +516,49 @@ fn process_message(msg: Message) -> ControlFlow<(), ()> {
     }
     ControlFlow::Continue(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::controller_events;
+    use crate::controller::{Controller, Status};
+    use crate::protocol::ServerMessage;
+
+    fn controller(path: &str, capacity: u8, status: Status) -> Controller {
+        Controller {
+            name: "DualSense".to_string(),
+            product_id: 0x0ce6,
+            vendor_id: 0x054c,
+            capacity,
+            status,
+            bluetooth: true,
+            serial_number: Some(path.trim_start_matches("/dev/").to_string()),
+            device_path: Some(path.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_controller_events() {
+        let previous = vec![
+            controller("/dev/hidraw1", 50, Status::Discharging),
+            controller("/dev/hidraw2", 50, Status::Discharging),
+            controller("/dev/hidraw3", 50, Status::Discharging),
+        ];
+        let current = vec![
+            controller("/dev/hidraw1", 50, Status::Discharging),
+            controller("/dev/hidraw2", 40, Status::Discharging),
+            controller("/dev/hidraw4", 90, Status::Charging),
+        ];
+
+        let events = controller_events(&previous, &current);
+        assert_eq!(events.len(), 3);
+        assert!(
+            matches!(&events[0], ServerMessage::ControllerUpdated(state) if state.id == "/dev/hidraw2" && state.controller.capacity == 40)
+        );
+        assert!(
+            matches!(&events[1], ServerMessage::Connected(state) if state.id == "/dev/hidraw4")
+        );
+        assert!(matches!(&events[2], ServerMessage::Disconnected(c) if c.id == "/dev/hidraw3"));
+
+        assert!(controller_events(&current, &current).is_empty());
+    }
+}
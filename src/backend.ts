@@ -1,15 +1,97 @@
-import { callable } from "@decky/api";
-import { IController } from "./types";
-
 const PORT: number = 33220;
 const HOST: string = `http://localhost:${PORT}`;
 
-export const getDebugSetting = async () => await callable<[string, boolean], boolean>("settings_getSetting")("debug", false);
-export const getNotificationsSetting = async () => await callable<[string, boolean], boolean>("settings_getSetting")("notifications", true);
-export const setDebugSetting = async (value: boolean) => await callable<[string, boolean], unknown>("settings_setSetting")("debug", value);
-export const setNotificationsSetting = async (value: boolean) => await callable<[string, boolean], unknown>("settings_setSetting")("notifications", value);
-export const settingsCommit = callable<[], unknown>("settings_commit");
-export const getControllers = async (): Promise<[IController]> => {
-  let res = await fetch(`${HOST}/controllers`);
+export interface IAlertSettings {
+  warningThreshold: number;
+  criticalThreshold: number;
+  warningRepeatMinutes: number;
+  criticalRepeatMinutes: number;
+}
+
+// Which kinds of notifications are sent
+export interface INotificationEvents {
+  lowBattery: boolean;
+  charged: boolean;
+  chargingStarted: boolean;
+  connected: boolean;
+  disconnected: boolean;
+  lostWhileLow: boolean;
+}
+
+// Quiet period in local time, days are "Mon" to "Sun", times are "HH:MM"
+export interface IQuietPeriod {
+  days: string[];
+  start: string;
+  end: string;
+}
+
+export interface IQuietSettings {
+  schedule: IQuietPeriod[];
+  suppressed: "drop" | "digest";
+}
+
+// Custom alert texts, with {name}, {nickname}, {capacity} and {remaining} placeholders
+export interface IMessageTemplates {
+  lowBattery?: string;
+  criticalBattery?: string;
+  charged?: string;
+  chargingStarted?: string;
+  connected?: string;
+  disconnected?: string;
+  lostWhileLow?: string;
+}
+
+export interface IControllerSettings extends Partial<IAlertSettings> {
+  nickname?: string;
+  templates?: IMessageTemplates;
+}
+
+// Endpoint the alerts are posted to. `events` lists the message types sent, e.g. "low_battery",
+// all of them when empty. With a `secret` requests are signed in the X-Signature-256 header.
+export interface IWebhookSettings {
+  url: string;
+  events: string[];
+  secret?: string;
+}
+
+// Broker the controllers are published to, with Home Assistant discovery under `discoveryPrefix`
+export interface IMqttSettings {
+  host: string;
+  port: number;
+  clientId: string;
+  username?: string;
+  password?: string;
+  topicPrefix: string;
+  discoveryPrefix: string;
+}
+
+export interface ISettings {
+  notifications: boolean;
+  // Alerts through the desktop's notification server, for the backend running without Decky
+  desktopNotifications: boolean;
+  debug: boolean;
+  alerts: IAlertSettings;
+  events: INotificationEvents;
+  quiet: IQuietSettings;
+  templates: IMessageTemplates;
+  // Overrides keyed by the controller's stable id
+  controllers: Record<string, IControllerSettings>;
+  webhooks: IWebhookSettings[];
+  // Left out when MQTT is off, updating it to null turns it off
+  mqtt?: IMqttSettings | null;
+}
+
+export const getSettings = async (): Promise<ISettings> => {
+  const res = await fetch(`${HOST}/settings`);
+  return await res.json();
+}
+
+// Only the given settings are changed, the backend persists them and applies them right away
+export const updateSettings = async (settings: Partial<ISettings>): Promise<ISettings> => {
+  const res = await fetch(`${HOST}/settings`, {
+    method: "PATCH",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(settings),
+  });
   return await res.json();
 }
@@ -17,7 +17,28 @@ type ControllerProps = {
   controller: IController;
 };
 
+// Estimates below this confidence are too rough to show
+const MIN_CONFIDENCE = 0.3;
+
+const formatMinutes = (minutes: number) =>
+  minutes < 60 ? `${minutes}m` : `${Math.floor(minutes / 60)}h${String(minutes % 60).padStart(2, "0")}`;
+
+const timeLeft = (controller: IController) => {
+  if ((controller.confidence ?? 0) < MIN_CONFIDENCE) {
+    return null;
+  }
+  if (controller.minutesRemaining !== undefined) {
+    return `~${formatMinutes(controller.minutesRemaining)} left`;
+  }
+  if (controller.minutesToFull !== undefined) {
+    return `~${formatMinutes(controller.minutesToFull)} to full`;
+  }
+  return null;
+};
+
 const Controller = ({ controller }: ControllerProps) => {
+  const estimate = timeLeft(controller);
+
   return (
     <PanelSectionRow>
       <div className={FieldWithSeparator}>
@@ -34,6 +55,10 @@ const Controller = ({ controller }: ControllerProps) => {
           {
             (controller.capacity > 0 || controller.status !== "unknown") &&
             <div className={gamepadDialogClasses.FieldChildrenInner}>
+              {
+                estimate &&
+                <span style={{ display: "inline-block", marginRight: "8px", opacity: 0.7 }}>{estimate}</span>
+              }
               {
                 // only show battery capacity for non-MS vendors unless capacity is > 0 and over BT
                 // since we don't have the battery capacity yet for Xbox over USB
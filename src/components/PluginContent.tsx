@@ -7,46 +7,47 @@ import RefreshButton from "./RefreshButton";
 import SettingsMenu from "./SettingsMenu";
 
 import * as backend from "../backend";
+import { refreshControllers, snoozeAll, subscribeControllers } from "../notifications";
 import { IController } from "../types";
 import ControllersView from "./ControllersView";
 
 const PluginContent = () => {
   const [debug, setDebug] = useState<boolean>(false);
   const [notifications, setNotifications] = useState<boolean>(true);
+  const [events, setEvents] = useState<backend.INotificationEvents | null>(null);
   const [controllers, setControllers] = useState<IController[]>([]);
 
-  // For fetching controller & settings data on render
-  useEffect(() => {
-    backend.getControllers()
-      .then(controllers => { setControllers(controllers); });
-
-    backend.getDebugSetting()
-      .then(debug => { setDebug(debug); });
+  // The backend pushes controller changes over the websocket, so just follow along
+  useEffect(() => subscribeControllers(setControllers), []);
 
-    backend.getNotificationsSetting()
-      .then(notifications => { setNotifications(notifications); });
+  // For fetching settings data on render
+  useEffect(() => {
+    backend.getSettings()
+      .then(settings => {
+        setDebug(settings.debug);
+        setNotifications(settings.notifications);
+        setEvents(settings.events);
+      });
   }, []);
 
   const onRefresh = () => {
-    backend
-      .getControllers()
-      .then(controllers => { setControllers(controllers); });
+    refreshControllers();
   };
 
   const onDebugChange = (e: boolean) => {
-    backend.setDebugSetting(e)
-      .then(async () => {
-        await backend.settingsCommit();
-        setDebug(e);
-      });
+    backend.updateSettings({ debug: e })
+      .then(settings => { setDebug(settings.debug); });
   };
 
   const onNotificationsChange = (e: boolean) => {
-    backend.setNotificationsSetting(e)
-      .then(async () => {
-        await backend.settingsCommit();
-        setNotifications(e);
-      });
+    backend.updateSettings({ notifications: e })
+      .then(settings => { setNotifications(settings.notifications); });
+  };
+
+  const onEventChange = (event: keyof backend.INotificationEvents, e: boolean) => {
+    if (!events) return;
+    backend.updateSettings({ events: { ...events, [event]: e } })
+      .then(settings => { setEvents(settings.events); });
   };
 
   return (
@@ -58,8 +59,11 @@ const PluginContent = () => {
       <SettingsMenu
         debug={debug}
         notifications={notifications}
+        events={events}
         onDebugChange={onDebugChange}
         onNotificationsChange={onNotificationsChange}
+        onEventChange={onEventChange}
+        onSnoozeAll={snoozeAll}
       />
     </PanelSection>
   );
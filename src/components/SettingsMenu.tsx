@@ -1,13 +1,27 @@
-import { PanelSection, PanelSectionRow, ToggleField } from "@decky/ui";
+import { ButtonItem, PanelSection, PanelSectionRow, ToggleField } from "@decky/ui";
+
+import { INotificationEvents } from "../backend";
 
 type SettingsMenuProps = {
   debug: boolean;
   notifications: boolean;
+  events: INotificationEvents | null;
   onDebugChange: (value: boolean) => void;
   onNotificationsChange: (value: boolean) => void;
+  onEventChange: (event: keyof INotificationEvents, value: boolean) => void;
+  onSnoozeAll: (minutes: number) => void;
 };
 
-const SettingsMenu = ({ debug, notifications, onDebugChange, onNotificationsChange }: SettingsMenuProps) => {
+const EVENT_LABELS: [keyof INotificationEvents, string][] = [
+  ["lowBattery", "Low battery"],
+  ["charged", "Fully charged"],
+  ["chargingStarted", "Started charging"],
+  ["connected", "Connected"],
+  ["disconnected", "Disconnected"],
+  ["lostWhileLow", "Lost while low"],
+];
+
+const SettingsMenu = ({ debug, notifications, events, onDebugChange, onNotificationsChange, onEventChange, onSnoozeAll }: SettingsMenuProps) => {
   return (
     <PanelSection title="Settings">
       <PanelSectionRow>
@@ -17,6 +31,22 @@ const SettingsMenu = ({ debug, notifications, onDebugChange, onNotificationsChan
           onChange={onNotificationsChange}
         />
       </PanelSectionRow>
+      {notifications && events && EVENT_LABELS.map(([event, label]) => (
+        <PanelSectionRow key={event}>
+          <ToggleField
+            label={label}
+            checked={events[event]}
+            onChange={(value) => onEventChange(event, value)}
+          />
+        </PanelSectionRow>
+      ))}
+      {notifications && (
+        <PanelSectionRow>
+          <ButtonItem layout="below" onClick={() => onSnoozeAll(60)}>
+            Quiet for 1 hour
+          </ButtonItem>
+        </PanelSectionRow>
+      )}
       <PanelSectionRow>
         <ToggleField
           label="Debug mode"
@@ -1,35 +1,117 @@
 import { toaster, ToastData } from '@decky/api';
 import { log, error } from './logger';
+import { clientHello, IControllerState, PROTOCOL_VERSION, refreshCommand, ServerMessage, snoozeAllCommand } from './protocol';
 
-export const setupNotifications = () => {
-  const handleMessage = (e: MessageEvent) => {
-    if (e.type !== 'text' || typeof e.data !== 'string') {
-      error('Unexpected message type', e.type);
-      return;
-    }
+type ControllersListener = (controllers: IControllerState[]) => void;
+
+// Live view of the controllers, kept up to date by the backend's snapshot and update messages
+const controllers = new Map<string, IControllerState>();
+const listeners = new Set<ControllersListener>();
+let socket: WebSocket | null = null;
+
+const notifyListeners = () => {
+  const current = [...controllers.values()];
+  listeners.forEach(listener => listener(current));
+};
+
+const replaceControllers = (states: IControllerState[]) => {
+  controllers.clear();
+  states.forEach(state => controllers.set(state.id, state));
+  notifyListeners();
+};
+
+// Calls `listener` with the current controllers and again whenever they change.
+// Returns a function that removes the listener.
+export const subscribeControllers = (listener: ControllersListener) => {
+  listeners.add(listener);
+  listener([...controllers.values()]);
+  return () => { listeners.delete(listener); };
+};
+
+// Asks the backend to check the controllers right away
+export const refreshControllers = () => {
+  if (socket?.readyState === WebSocket.OPEN) {
+    socket.send(refreshCommand());
+  }
+};
 
+// Holds back every notification for a while, they are delivered as a digest afterwards
+export const snoozeAll = (minutes: number) => {
+  if (socket?.readyState === WebSocket.OPEN) {
+    socket.send(snoozeAllCommand(minutes));
+  }
+};
+
+export const setupNotifications = () => {
+  const toast = (body: string) => {
     const toastData: ToastData = {
       title: "Controller Tools",
-      body: e.data,
+      body,
       showToast: true,
     }
 
     toaster.toast(toastData);
   }
 
+  const handleMessage = (ws: WebSocket, e: MessageEvent) => {
+    if (e.type !== 'message' || typeof e.data !== 'string') {
+      error('Unexpected message type', e.type);
+      return;
+    }
+
+    const message: ServerMessage = JSON.parse(e.data);
+    switch (message.type) {
+      case 'hello':
+        if (message.payload.protocolVersion !== PROTOCOL_VERSION) {
+          error('Backend speaks protocol version', message.payload.protocolVersion, 'expected', PROTOCOL_VERSION);
+        }
+        ws.send(clientHello());
+        break;
+      case 'snapshot':
+        replaceControllers(message.payload.controllers);
+        break;
+      case 'connected':
+      case 'controller_updated':
+        controllers.set(message.payload.id, message.payload);
+        notifyListeners();
+        break;
+      case 'disconnected':
+        controllers.delete(message.payload.id);
+        notifyListeners();
+        break;
+      case 'low_battery':
+      case 'charged':
+      case 'charging_started':
+      case 'lost_while_low':
+      case 'connected_alert':
+      case 'disconnected_alert':
+      case 'digest':
+      case 'alert_group':
+        toast(message.payload.message);
+        break;
+      case 'error':
+        error('Backend error', message.payload.code, message.payload.message);
+        break;
+      default:
+        log('Received', message.type);
+    }
+  }
+
   const setupWebsocket = (): void => {
     const ws = new WebSocket('ws://localhost:33220/ws');
+    socket = ws;
 
     ws.onopen = () => {
       log('WebSocket connected');
     };
 
     ws.onmessage = (e: MessageEvent) => {
-      handleMessage(e);
+      handleMessage(ws, e);
     };
 
     ws.onclose = (e: CloseEvent) => {
       log('Socket is closed. Reconnect will be attempted in 10 seconds.', e.reason);
+      socket = null;
       setTimeout(() => {
         setupWebsocket();
       }, 10000);
@@ -42,4 +124,4 @@ export const setupNotifications = () => {
   }
 
   setupWebsocket();
-}
\ No newline at end of file
+}
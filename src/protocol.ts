@@ -0,0 +1,61 @@
+import { IController } from "./types";
+
+// Must match PROTOCOL_VERSION in backend/src/protocol.rs
+export const PROTOCOL_VERSION: number = 1;
+
+export interface IEnvelope<T extends string, P> {
+  type: T;
+  version: number;
+  payload: P;
+}
+
+export interface IControllerState extends IController {
+  id: string;
+  // Survives reconnects, used by the history endpoints
+  stableId: string;
+}
+
+export interface IBatteryAlert {
+  id: string;
+  stableId: string;
+  vendorId: number;
+  name: string;
+  capacity: number;
+  message: string;
+  critical: boolean;
+}
+
+export type ServerMessage =
+  | IEnvelope<"hello", { server: string; serverVersion: string; protocolVersion: number }>
+  | IEnvelope<"snapshot", { controllers: IControllerState[] }>
+  | IEnvelope<"controller_updated", IControllerState>
+  | IEnvelope<"connected", IControllerState>
+  | IEnvelope<"disconnected", { id: string; name: string }>
+  | IEnvelope<"low_battery", IBatteryAlert>
+  | IEnvelope<"charged", IBatteryAlert>
+  | IEnvelope<"charging_started", IBatteryAlert>
+  | IEnvelope<"lost_while_low", IBatteryAlert>
+  | IEnvelope<"connected_alert", IBatteryAlert>
+  | IEnvelope<"disconnected_alert", IBatteryAlert>
+  | IEnvelope<"digest", { message: string; alerts: string[] }>
+  | IEnvelope<"alert_group", { message: string; critical: boolean; alerts: IBatteryAlert[] }>
+  | IEnvelope<"error", { code: string; message: string }>
+  | IEnvelope<"response", unknown>;
+
+export const clientHello = (): string => JSON.stringify({
+  type: "hello",
+  version: PROTOCOL_VERSION,
+  payload: { client: "decky" },
+});
+
+export const refreshCommand = (): string => JSON.stringify({
+  type: "refresh",
+  version: PROTOCOL_VERSION,
+});
+
+// Holds back every alert for `minutes`, 0 ends the snooze
+export const snoozeAllCommand = (minutes: number): string => JSON.stringify({
+  type: "snooze_all",
+  version: PROTOCOL_VERSION,
+  payload: { minutes },
+});
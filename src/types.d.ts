@@ -20,4 +20,8 @@ export interface IController {
   capacity: number;
   status: string;
   bluetooth: boolean;
+  // Estimated by the backend once it has enough history
+  minutesRemaining?: number;
+  minutesToFull?: number;
+  confidence?: number;
 }